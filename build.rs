@@ -7,6 +7,7 @@ use std::process::Command;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
+    // makefile.cargo builds a stand-in for the C glue with the stub backend.
     let result = Command::new("make")
         .args(&["-f", "makefile.cargo"])
         .status()
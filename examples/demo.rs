@@ -2,13 +2,18 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+#[cfg(not(feature = "stub-backend"))]
 extern crate android_logger;
+#[cfg(feature = "stub-backend")]
+extern crate env_logger;
 extern crate gonk_gfx;
 #[macro_use]
 extern crate log;
 
+#[cfg(not(feature = "stub-backend"))]
 use gonk_gfx::window;
 
+#[cfg(not(feature = "stub-backend"))]
 fn main() {
     android_logger::init_once(log::LogLevel::Info);
 
@@ -21,3 +26,10 @@ fn main() {
         ::std::thread::sleep_ms(100000);
     }
 }
+
+// The window needs the device's EGL.
+#[cfg(feature = "stub-backend")]
+fn main() {
+    env_logger::init().unwrap();
+    error!("The demo needs a device, try the stub example instead");
+}
@@ -0,0 +1,68 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Runs a few frames through the stub backend and saves the last one.
+// Build with `RUST_LOG=info cargo run --features stub-backend --example stub`.
+
+extern crate env_logger;
+extern crate gonk_gfx;
+#[macro_use]
+extern crate log;
+
+use gonk_gfx::gonk_gfx::*;
+use gonk_gfx::hwc::{HwcDevice, HWC_DISPLAY_PRIMARY};
+use gonk_gfx::stub;
+use gonk_gfx::sync::Fence;
+use std::path::Path;
+use std::sync::mpsc::channel;
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let hwc = HwcDevice::new().expect("Failed to get the stub HWC device");
+    let (width, height, _dpi) = hwc.get_dimensions_and_dpi();
+    let configs = hwc.display_configs(HWC_DISPLAY_PRIMARY).unwrap_or_default();
+    for config in &configs {
+        info!("{:?}", config);
+    }
+
+    let usage = GRALLOC_USAGE_HW_FB | GRALLOC_USAGE_HW_RENDER | GRALLOC_USAGE_HW_COMPOSER;
+    let window = GonkNativeWindow::new(hwc.native(), width, height, usage)
+        .expect("Failed to create the native window");
+
+    unsafe {
+        (*window)
+            .alloc_buffers()
+            .expect("Failed to allocate buffers");
+        if let Some(config) = configs.last() {
+            hwc.set_active_config(HWC_DISPLAY_PRIMARY, config)
+                .expect("Failed to switch display mode");
+            (*window)
+                .set_default_dimensions(config.width, config.height)
+                .expect("Failed to resize window");
+        }
+        (*window)
+            .connect(NATIVE_WINDOW_API_CPU)
+            .expect("Failed to connect to the window");
+        let (present_sender, presents) = channel();
+        (*window).set_present_sender(Some(present_sender));
+        for color in &[
+            [0xff, 0, 0, 0xff],
+            [0, 0xff, 0, 0xff],
+            [0, 0xff, 0xff, 0xff],
+        ] {
+            let (buf, _fence) = (*window).dequeue().expect("No buffer to dequeue");
+            stub::fill_buffer(buf, *color);
+            (*window).queue(buf, Fence::none()).expect("Failed to queue buffer");
+            info!("{:?}", presents.recv().expect("No present info"));
+        }
+        info!("{:?}", (*window).stats());
+        (*window)
+            .disconnect(NATIVE_WINDOW_API_CPU)
+            .expect("Failed to disconnect from the window");
+        ((*window).window.common.dec_ref)(&mut (*window).window.common);
+    }
+
+    stub::save_ppm(&hwc, Path::new("stub.ppm")).expect("Failed to save stub.ppm");
+}
@@ -0,0 +1,114 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Errors reported by the HALs, as a Rust type.
+
+use libc::{c_int, EBUSY, EINVAL, ENODEV, ENOENT, ENOMEM, ENOSYS, ENOTCONN, ENOTSUP, ETIMEDOUT};
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GonkGfxError {
+    OutOfMemory,
+    Unsupported,
+    InvalidArgument,
+    ModuleNotFound,
+    /// Every window buffer is dequeued; queue or cancel one first.
+    NoBufferAvailable,
+    /// No producer is connected to the window, see
+    /// `GonkNativeWindow::connect`.
+    NotConnected,
+    /// Waiting for something, e.g. a buffer to dequeue, took too long.
+    TimedOut,
+    /// Any other HAL failure, with its (negative) status code.
+    DeviceError(i32),
+}
+
+impl GonkGfxError {
+    /// Maps a negative errno-style status returned by a HAL. -EBUSY stays a
+    /// `DeviceError`: a busy HAL doesn't mean the window ran out of buffers.
+    pub fn from_status(status: c_int) -> GonkGfxError {
+        match -status {
+            ENOMEM => GonkGfxError::OutOfMemory,
+            ENOSYS | ENOTSUP => GonkGfxError::Unsupported,
+            EINVAL => GonkGfxError::InvalidArgument,
+            ENOENT | ENODEV => GonkGfxError::ModuleNotFound,
+            ENOTCONN => GonkGfxError::NotConnected,
+            ETIMEDOUT => GonkGfxError::TimedOut,
+            _ => GonkGfxError::DeviceError(status),
+        }
+    }
+
+    /// The negative errno-style status to hand back across the C ABI.
+    pub fn status(&self) -> c_int {
+        match *self {
+            GonkGfxError::OutOfMemory => -ENOMEM,
+            GonkGfxError::Unsupported => -ENOSYS,
+            GonkGfxError::InvalidArgument => -EINVAL,
+            GonkGfxError::ModuleNotFound => -ENOENT,
+            GonkGfxError::NoBufferAvailable => -EBUSY,
+            GonkGfxError::NotConnected => -ENOTCONN,
+            GonkGfxError::TimedOut => -ETIMEDOUT,
+            GonkGfxError::DeviceError(status) => status,
+        }
+    }
+}
+
+impl fmt::Display for GonkGfxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GonkGfxError::OutOfMemory => write!(f, "out of memory"),
+            GonkGfxError::Unsupported => write!(f, "operation not supported"),
+            GonkGfxError::InvalidArgument => write!(f, "invalid argument"),
+            GonkGfxError::ModuleNotFound => write!(f, "HAL module not found"),
+            GonkGfxError::NoBufferAvailable => write!(f, "no buffer available"),
+            GonkGfxError::NotConnected => write!(f, "no producer connected"),
+            GonkGfxError::TimedOut => write!(f, "timed out"),
+            GonkGfxError::DeviceError(status) => write!(f, "device error {}", status),
+        }
+    }
+}
+
+impl Error for GonkGfxError {
+    fn description(&self) -> &str {
+        match *self {
+            GonkGfxError::OutOfMemory => "out of memory",
+            GonkGfxError::Unsupported => "operation not supported",
+            GonkGfxError::InvalidArgument => "invalid argument",
+            GonkGfxError::ModuleNotFound => "HAL module not found",
+            GonkGfxError::NoBufferAvailable => "no buffer available",
+            GonkGfxError::NotConnected => "no producer connected",
+            GonkGfxError::TimedOut => "timed out",
+            GonkGfxError::DeviceError(_) => "device error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_is_a_device_error() {
+        assert_eq!(
+            GonkGfxError::from_status(-EBUSY),
+            GonkGfxError::DeviceError(-EBUSY)
+        );
+    }
+
+    #[test]
+    fn statuses_round_trip() {
+        for &err in &[
+            GonkGfxError::OutOfMemory,
+            GonkGfxError::Unsupported,
+            GonkGfxError::InvalidArgument,
+            GonkGfxError::ModuleNotFound,
+            GonkGfxError::NotConnected,
+            GonkGfxError::TimedOut,
+            GonkGfxError::DeviceError(-EBUSY),
+        ] {
+            assert_eq!(GonkGfxError::from_status(err.status()), err);
+        }
+    }
+}
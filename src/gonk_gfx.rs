@@ -4,28 +4,645 @@
 
 // Low level Gonk graphics using the hardware composer.
 
+use errno::errno;
+use error::GonkGfxError;
 use gralloc::*;
 use hwc::*;
-use libc::{c_int, c_void, close, size_t};
-use std::mem::{size_of, transmute, zeroed};
+use libc::{
+    c_int, c_void, close, free, malloc, poll, pollfd, size_t, EAGAIN, EBUSY, EINTR, EINVAL, ENODEV,
+    POLLIN,
+};
+use std::mem::{replace, size_of, transmute, zeroed};
 use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use sync::Fence;
+use vsync::VsyncWaker;
 
+const LOG_TARGET: &'static str = "gonk-gfx";
+
+pub const GRALLOC_USAGE_SW_READ_OFTEN: c_int = 0x00000003;
 pub const GRALLOC_USAGE_HW_TEXTURE: c_int = 0x00000100;
 pub const GRALLOC_USAGE_HW_RENDER: c_int = 0x00000200;
 pub const GRALLOC_USAGE_HW_2D: c_int = 0x00000400;
 pub const GRALLOC_USAGE_HW_COMPOSER: c_int = 0x00000800;
 pub const GRALLOC_USAGE_HW_FB: c_int = 0x00001000;
 
+// What the HWC needs to scan buffers out, see GonkNativeWindowBuilder.
+const DEFAULT_CONSUMER_USAGE: c_int = GRALLOC_USAGE_HW_COMPOSER | GRALLOC_USAGE_HW_FB;
+
+// Weight of the latest frame in FrameStats::avg_set_ns, as 1/N.
+const STATS_AVERAGE_WINDOW: u64 = 16;
+
+/// Timings of the HWC calls made to present frames, and of the producer's
+/// calls, in nanoseconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameStats {
+    pub frames: u64,
+    /// Frames not sent to the HWC since nothing changed, in static mode.
+    pub skipped_frames: u64,
+    pub last_prepare_ns: u64,
+    pub last_set_ns: u64,
+    /// Moving average of the set() duration over the last frames.
+    pub avg_set_ns: u64,
+    /// How long the last dequeue_buffer and queue_buffer calls took, the
+    /// latter including the wait for vsync and the HWC calls.
+    pub last_dequeue_ns: u64,
+    pub last_queue_ns: u64,
+    /// Buffers handed to the producer.
+    pub dequeued: u64,
+}
+
+impl FrameStats {
+    fn record(&mut self, prepare: Duration, set: Duration) {
+        self.last_prepare_ns = duration_ns(prepare);
+        self.last_set_ns = duration_ns(set);
+        self.frames += 1;
+        let weight = if self.frames < STATS_AVERAGE_WINDOW {
+            self.frames
+        } else {
+            STATS_AVERAGE_WINDOW
+        };
+        self.avg_set_ns = (self.avg_set_ns * (weight - 1) + self.last_set_ns) / weight;
+    }
+}
+
+fn duration_ns(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+/// The pixels of a window buffer, see `GonkNativeWindow::capture`.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub width: i32,
+    pub height: i32,
+    /// The distance between the rows of `data`, in pixels. The rows of
+    /// packed formats are copied without the buffer's padding, so this is
+    /// the width for them.
+    pub stride: i32,
+    pub format: PixelFormat,
+    /// How the channels of `format` are laid out in `data`, None for YV12.
+    pub channel_order: Option<ChannelOrder>,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Returns the pixels as RGBA bytes, the layout PNG encoders take,
+    /// whatever the format. Formats without alpha get an opaque one, RGB565
+    /// is widened to 8 bits per channel and YV12 is converted as BT.601.
+    /// Empty for the formats gralloc lays out as it likes.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let pixels = self.width as usize * self.height as usize;
+        let mut rgba = Vec::with_capacity(pixels * 4);
+        match self.channel_order {
+            Some(ChannelOrder::Rgba) => rgba.extend_from_slice(&self.data),
+            Some(ChannelOrder::Rgbx) => {
+                for p in self.data.chunks(4) {
+                    rgba.extend_from_slice(&[p[0], p[1], p[2], 0xff]);
+                }
+            }
+            Some(ChannelOrder::Rgb) => {
+                for p in self.data.chunks(3) {
+                    rgba.extend_from_slice(&[p[0], p[1], p[2], 0xff]);
+                }
+            }
+            Some(ChannelOrder::Bgra) => {
+                for p in self.data.chunks(4) {
+                    rgba.extend_from_slice(&[p[2], p[1], p[0], p[3]]);
+                }
+            }
+            Some(ChannelOrder::Rgb565) => {
+                for p in self.data.chunks(2) {
+                    let pixel = p[0] as u16 | (p[1] as u16) << 8;
+                    let r = (pixel >> 11) as u8 & 0x1f;
+                    let g = (pixel >> 5) as u8 & 0x3f;
+                    let b = pixel as u8 & 0x1f;
+                    rgba.extend_from_slice(&[
+                        r << 3 | r >> 2,
+                        g << 2 | g >> 4,
+                        b << 3 | b >> 2,
+                        0xff,
+                    ]);
+                }
+            }
+            None if self.format == PixelFormat::Yv12 => self.yv12_to_rgba8(&mut rgba),
+            None => (),
+        }
+        rgba
+    }
+
+    // The planes are laid out as buffer_byte_size() expects, with the Cr
+    // plane before the Cb one.
+    fn yv12_to_rgba8(&self, rgba: &mut Vec<u8>) {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let stride = self.stride as usize;
+        let c_stride = (stride / 2 + 15) & !15;
+        let cr_plane = stride * height;
+        let cb_plane = cr_plane + c_stride * (height / 2);
+        let clamp = |value: i32| (value >> 8).max(0).min(255) as u8;
+        for y in 0..height {
+            for x in 0..width {
+                let luma = 298 * (self.data[y * stride + x] as i32 - 16) + 128;
+                let c = y / 2 * c_stride + x / 2;
+                let cr = self.data[cr_plane + c] as i32 - 128;
+                let cb = self.data[cb_plane + c] as i32 - 128;
+                rgba.extend_from_slice(&[
+                    clamp(luma + 409 * cr),
+                    clamp(luma - 100 * cb - 208 * cr),
+                    clamp(luma + 516 * cb),
+                    0xff,
+                ]);
+            }
+        }
+    }
+}
+
+/// Sent once a queued frame reached the screen, see
+/// `GonkNativeWindow::set_present_sender`.
+#[derive(Clone, Copy, Debug)]
+pub struct PresentInfo {
+    /// The window buffer slot the frame was queued in.
+    pub buffer_index: usize,
+    /// When the HWC's retire fence signaled, or when set() returned if the
+    /// HWC didn't hand out a retire fence.
+    pub retire_fence_signaled_at: Instant,
+}
+
+/// What the HWC's prepare() decided for the last frame, see
+/// `GonkNativeWindow::composition`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompositionResult {
+    /// Whether a layer other than the window's own was left to GL
+    /// composition into the framebuffer target. When false, whatever the
+    /// HWC shows besides the window's content needs no GL work.
+    pub gl_needed: bool,
+    /// The layers the HWC took as HWC_OVERLAY.
+    pub overlay_count: usize,
+}
+
+impl CompositionResult {
+    // `layers` besides the window's skip layer and the framebuffer target.
+    fn from_layers<'a, I>(layers: I) -> CompositionResult
+    where
+        I: Iterator<Item = &'a hwc_layer>,
+    {
+        let mut result = CompositionResult::default();
+        for layer in layers {
+            match layer.composition_type {
+                HWC_FRAMEBUFFER => result.gl_needed = true,
+                HWC_OVERLAY => result.overlay_count += 1,
+                _ => (),
+            }
+        }
+        result
+    }
+}
+
+/// A snapshot of a layer the window sent to the HWC, see
+/// `GonkNativeWindow::last_layers`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LayerInfo {
+    /// What prepare() decided, HWC_FRAMEBUFFER, HWC_OVERLAY and so on.
+    pub composition_type: i32,
+    pub flags: u32,
+    /// The address of the buffer handle, or the packed color of an
+    /// HWC_BACKGROUND layer.
+    pub handle: usize,
+    /// Widened to float for HWCs older than 1.3.
+    pub source_crop: hwc_frect,
+    pub display_frame: hwc_rect,
+    /// The fence numbers as of set(). They belong to whoever the layer
+    /// handed them to, and may be closed by now.
+    pub acquire_fence_fd: c_int,
+    pub release_fence_fd: c_int,
+}
+
+impl LayerInfo {
+    fn from_layer(layer: &hwc_layer, float_crop: bool) -> LayerInfo {
+        let source_crop = unsafe {
+            if float_crop {
+                layer.source_crop.source_crop_f
+            } else {
+                hwc_frect::from(layer.source_crop.source_crop_i)
+            }
+        };
+        LayerInfo {
+            composition_type: layer.composition_type,
+            flags: layer.flags,
+            handle: layer.handle as usize,
+            source_crop,
+            display_frame: layer.display_frame,
+            acquire_fence_fd: layer.acquire_fence_fd,
+            release_fence_fd: layer.release_fence_fd,
+        }
+    }
+}
+
+// What the HWC plans composition around, as opposed to the buffers and
+// fences which change every frame.
+#[derive(Clone, Copy, PartialEq)]
+struct FrameGeometry {
+    display_frame: hwc_rect,
+    source_crop: hwc_frect,
+    transform: c_int,
+    background: bool,
+    // Source crop, display frame, plane alpha and blending.
+    overlay: Option<(hwc_frect, hwc_rect, u8, Blending)>,
+}
+
+// The display contents draw() hands the HWC, kept across frames rather than
+// rebuilt for each. Boxed, as the layers' visible regions point into it.
+struct DisplayContents {
+    list: hwc_display_contents,
+    target_visible: hwc_rect,
+    overlay_visible: hwc_rect,
+    // Of the last set(), None to have the HWC plan the next frame anew.
+    geometry: Option<FrameGeometry>,
+}
+
+impl DisplayContents {
+    fn new() -> Box<DisplayContents> {
+        Box::new(DisplayContents {
+            list: unsafe { zeroed() },
+            target_visible: hwc_rect::new(0, 0, 0, 0),
+            overlay_visible: hwc_rect::new(0, 0, 0, 0),
+            geometry: None,
+        })
+    }
+}
+
+// Clears `layer` for another frame, with no buffer nor fences.
+fn reset_layer(layer: &mut hwc_layer, composition_type: i32) -> &mut hwc_layer {
+    *layer = unsafe { zeroed() };
+    layer.composition_type = composition_type;
+    layer.acquire_fence_fd = -1;
+    layer.release_fence_fd = -1;
+    layer.plane_alpha = 0xff;
+    layer
+}
+
+// Closes the fences a failed set() may have left in `list`, e.g. before
+// trying it again.
+fn close_set_fences(list: &mut hwc_display_contents, num_layers: usize) {
+    drop(Fence::from_raw(replace(&mut list.retire_fence_fd, -1)));
+    for layer in &mut list.hw_layers[..num_layers] {
+        drop(Fence::from_raw(replace(&mut layer.release_fence_fd, -1)));
+    }
+}
+
+// How long prepare() and set() may take, see set_frame_budget().
+struct FrameBudget {
+    budget: Duration,
+    // When the last warning was logged, and the frames over budget since.
+    last_warning: Option<Instant>,
+    frames_over: u32,
+}
+
+impl FrameBudget {
+    fn check(&mut self, spent: Duration) {
+        if spent <= self.budget {
+            return;
+        }
+        self.frames_over += 1;
+        let now = Instant::now();
+        if let Some(last) = self.last_warning {
+            if now - last < Duration::from_millis(FRAME_BUDGET_WARNING_PERIOD_MS) {
+                return;
+            }
+        }
+        warn!(
+            target: LOG_TARGET,
+            "Composition took {:?}, {:?} over the {:?} budget ({} frames over it since the last warning)",
+            spent,
+            spent - self.budget,
+            self.budget,
+            self.frames_over
+        );
+        self.last_warning = Some(now);
+        self.frames_over = 0;
+    }
+}
+
+// A buffer shown in its own layer, above the window's buffers.
+struct Overlay {
+    handle: *const native_handle,
+    source_crop: hwc_frect,
+    display_frame: hwc_rect,
+    // Whether the HWC took it as HWC_OVERLAY in the last prepare().
+    accepted: bool,
+    // From the last set(), until taken.
+    release_fence: Fence,
+}
+
+// Blocks until a sync fence signals, then closes it.
+fn wait_fence(fence: Fence) {
+    wait_fence_until(fence, None);
+}
+
+// Like wait_fence, giving up at `deadline`. Returns whether it signaled.
+fn wait_fence_until(fence: Fence, deadline: Option<Instant>) -> bool {
+    if fence.is_none() {
+        return true;
+    }
+    let fd = fence.as_raw();
+    let mut pfd = pollfd {
+        fd: fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    let signaled = loop {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    break false;
+                }
+                // Rounded up, so we don't spin on a sub-millisecond remainder.
+                ((duration_ns(deadline - now) + 999_999) / 1_000_000) as c_int
+            }
+            None => -1,
+        };
+        let ret = unsafe { poll(&mut pfd, 1, timeout) };
+        if ret >= 0 || errno().0 != EINTR {
+            if ret < 0 {
+                error!(target: LOG_TARGET, "Failed to wait on fence {}", fd);
+            }
+            // 0 means we timed out, let the loop check the deadline.
+            if ret != 0 || deadline.is_none() {
+                break ret > 0;
+            }
+        }
+    };
+    signaled
+}
+
+// Waits on retire fences off the producer's thread and reports the frames
+// to `sender`. Stops when the window or the receiver goes away.
+// The fences still in flight are closed along with the receiver.
+fn spawn_present_thread(sender: Sender<PresentInfo>) -> Sender<(usize, Fence, Instant)> {
+    let (fence_sender, fence_receiver) = channel::<(usize, Fence, Instant)>();
+    thread::spawn(move || {
+        for (buffer_index, fence, set_end) in fence_receiver.iter() {
+            let retire_fence_signaled_at = if !fence.is_none() {
+                wait_fence(fence);
+                Instant::now()
+            } else {
+                set_end
+            };
+            let info = PresentInfo {
+                buffer_index,
+                retire_fence_signaled_at,
+            };
+            if sender.send(info).is_err() {
+                break;
+            }
+        }
+    });
+    fence_sender
+}
+
+// Lets a blocking dequeue_buffer sleep until queue_buffer or cancel_buffer
+// hands a buffer back. The lock is held by all three, so a slot can't be
+// returned between a failed dequeue and the wait.
+#[derive(Default)]
+struct BufferWait {
+    lock: Mutex<()>,
+    released: Condvar,
+    // Held through queue_buffer, which presents without `lock`, so that
+    // waiting for vsync or for the HWC doesn't hold up dequeue_buffer. A
+    // dequeue that allocates the buffers takes it too.
+    presenting: Mutex<()>,
+}
+
+// What a window needs to allocate its buffers, off its own thread if need be.
+#[derive(Clone)]
+struct BufferSetSpec {
+    dev: Arc<AllocDevice>,
+    width: i32,
+    height: i32,
+    format: PixelFormat,
+    usage: c_int,
+    count: usize,
+    generation: u32,
+}
+
+// Buffers nobody else has a reference to yet, until installed in a window.
+struct BufferSet {
+    bufs: Vec<*mut GonkNativeWindowBuffer>,
+    format: PixelFormat,
+}
+
+unsafe impl Send for BufferSet {}
+
+impl BufferSetSpec {
+    // Tries the format, then its fallbacks, see PixelFormat::fallback.
+    fn alloc(&self) -> Result<BufferSet, GonkGfxError> {
+        let mut format = self.format;
+        loop {
+            match self.alloc_in(format) {
+                Ok(bufs) => return Ok(BufferSet { bufs, format }),
+                Err(err) => match format.fallback() {
+                    Some(fallback) => {
+                        warn!(
+                            target: LOG_TARGET,
+                            "Failed to allocate {:?} buffers ({}), trying {:?}",
+                            format, err, fallback
+                        );
+                        format = fallback;
+                    }
+                    None => return Err(err),
+                },
+            }
+        }
+    }
+
+    fn alloc_in(
+        &self,
+        format: PixelFormat,
+    ) -> Result<Vec<*mut GonkNativeWindowBuffer>, GonkGfxError> {
+        let mut bufs = Vec::with_capacity(self.count);
+        for _ in 0..self.count {
+            match GonkNativeWindowBuffer::new(
+                &self.dev,
+                self.width,
+                self.height,
+                format.as_gralloc(),
+                self.usage,
+            ) {
+                Ok(buf) => unsafe {
+                    (*buf).generation = self.generation;
+                    bufs.push(buf);
+                },
+                Err(err) => {
+                    for buf in bufs {
+                        unsafe {
+                            ((*buf).buffer.common.dec_ref)(&mut (*buf).buffer.common);
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(bufs)
+    }
+}
+
+// EGL clamps eglSwapInterval() to this.
+const MAX_SWAP_INTERVAL: c_int = 1;
+
+// How long to wait for a vsync before presenting anyway, e.g. if vsync
+// events were turned off.
+const VSYNC_TIMEOUT_MS: u64 = 100;
+
+// How long drain() waits for the HWC to release the buffers, in total.
+const FENCE_DRAIN_TIMEOUT_MS: u64 = 1000;
+
+// How often the summary set_log_summary() turns on is logged.
+const LOG_SUMMARY_PERIOD_MS: u64 = 1000;
+
+// How often draw() warns at most about frames over the budget set with
+// set_frame_budget().
+const FRAME_BUDGET_WARNING_PERIOD_MS: u64 = 1000;
+
+// How long to wait before trying a set() again that failed transiently,
+// e.g. with -EBUSY during a mode change. About a frame.
+const SET_RETRY_DELAY_MS: u64 = 16;
+
+// Number of buffers a window cycles through unless the producer asks otherwise.
+const DEFAULT_BUFFER_COUNT: usize = 2;
+
 // system/core/include/cutils/native_handle.h
 
 #[repr(C)]
 pub struct native_handle {
-    version: c_int,
-    num_fds: c_int,
-    num_ints: c_int,
+    pub version: c_int,
+    pub num_fds: c_int,
+    pub num_ints: c_int,
     data: [c_int; 0],
 }
 
+impl native_handle {
+    /// A handle header. Its `num_fds` fds and `num_ints` ints must follow it
+    /// in memory.
+    pub fn new(num_fds: c_int, num_ints: c_int) -> native_handle {
+        native_handle {
+            version: size_of::<native_handle>() as c_int,
+            num_fds,
+            num_ints,
+            data: [],
+        }
+    }
+}
+
+const NATIVE_HANDLE_MAX_FDS: c_int = 1024;
+const NATIVE_HANDLE_MAX_INTS: c_int = 1024;
+
+impl native_handle {
+    /// Checks the header of a handle, e.g. one received from another process,
+    /// before handing it to gralloc.
+    pub fn is_valid(&self) -> bool {
+        self.version == size_of::<native_handle>() as c_int
+            && self.num_fds >= 0
+            && self.num_fds <= NATIVE_HANDLE_MAX_FDS
+            && self.num_ints >= 0
+            && self.num_ints <= NATIVE_HANDLE_MAX_INTS
+    }
+
+    /// The fds following the header, e.g. the dma-buf to share the buffer
+    /// with another process. Empty if the header isn't valid.
+    pub fn fds(&self) -> &[c_int] {
+        if !self.is_valid() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data.as_ptr(), self.num_fds as usize) }
+    }
+
+    /// The ints following the fds, whose meaning is up to gralloc.
+    pub fn ints(&self) -> &[c_int] {
+        if !self.is_valid() {
+            return &[];
+        }
+        unsafe {
+            slice::from_raw_parts(
+                self.data.as_ptr().offset(self.num_fds as isize),
+                self.num_ints as usize,
+            )
+        }
+    }
+}
+
+/// The fds of `handle`, see `native_handle::fds`. Empty for a null handle.
+pub fn handle_fds<'a>(handle: *const native_handle) -> &'a [c_int] {
+    if handle.is_null() {
+        return &[];
+    }
+    unsafe { (*handle).fds() }
+}
+
+/// The ints of `handle`, see `native_handle::ints`. Empty for a null handle.
+pub fn handle_ints<'a>(handle: *const native_handle) -> &'a [c_int] {
+    if handle.is_null() {
+        return &[];
+    }
+    unsafe { (*handle).ints() }
+}
+
+/// Splits `handle` into the fds to send to another process as SCM_RIGHTS,
+/// and the ints to send as regular data, for `reconstruct_handle` to put
+/// back together there. The fds stay owned by the handle; the kernel dups
+/// them on the way.
+pub fn serialize_handle(handle: *const native_handle) -> (Vec<c_int>, Vec<c_int>) {
+    (handle_fds(handle).to_vec(), handle_ints(handle).to_vec())
+}
+
+/// Builds a handle out of fds and ints received from `serialize_handle`,
+/// as native_handle_create() would, to `register_buffer` with gralloc. The
+/// handle owns the fds from then on; free it with `delete_handle`. Null if
+/// there are too many of either.
+pub fn reconstruct_handle(fds: &[c_int], ints: &[c_int]) -> *const native_handle {
+    if fds.len() > NATIVE_HANDLE_MAX_FDS as usize || ints.len() > NATIVE_HANDLE_MAX_INTS as usize {
+        error!(
+            target: LOG_TARGET,
+            "Too many fds ({}) or ints ({}) for a handle",
+            fds.len(),
+            ints.len()
+        );
+        return ptr::null();
+    }
+    let size = size_of::<native_handle>() + size_of::<c_int>() * (fds.len() + ints.len());
+    unsafe {
+        let handle = malloc(size) as *mut native_handle;
+        if handle.is_null() {
+            error!(target: LOG_TARGET, "Failed to allocate a handle");
+            return ptr::null();
+        }
+        *handle = native_handle::new(fds.len() as c_int, ints.len() as c_int);
+        let data = (*handle).data.as_mut_ptr();
+        ptr::copy_nonoverlapping(fds.as_ptr(), data, fds.len());
+        ptr::copy_nonoverlapping(ints.as_ptr(), data.offset(fds.len() as isize), ints.len());
+        handle
+    }
+}
+
+/// Closes the fds of a handle from `reconstruct_handle` and frees it, as
+/// native_handle_close() and native_handle_delete() do. Unregister it from
+/// gralloc first.
+pub fn delete_handle(handle: *const native_handle) {
+    if handle.is_null() {
+        return;
+    }
+    unsafe {
+        for &fd in (*handle).fds() {
+            close(fd);
+        }
+        free(handle as *mut c_void);
+    }
+}
+
 // system/core/include/system/window.h
 
 #[repr(C)]
@@ -33,7 +650,7 @@ pub struct ANativeBase {
     magic: u32,
     version: u32,
     reserved: [isize; 4],
-    inc_ref: extern "C" fn(*mut ANativeBase),
+    pub inc_ref: extern "C" fn(*mut ANativeBase),
     pub dec_ref: extern "C" fn(*mut ANativeBase),
 }
 
@@ -50,6 +667,13 @@ pub struct ANativeWindowBuffer {
     reserved_proc: [*mut c_void; 8],
 }
 
+impl ANativeWindowBuffer {
+    /// The gralloc handle of the buffer, owned by the buffer.
+    pub fn handle(&self) -> *const native_handle {
+        self.handle
+    }
+}
+
 #[repr(C)]
 pub struct ANativeWindow {
     pub common: ANativeBase,
@@ -81,17 +705,87 @@ pub struct GonkNativeWindow {
     set_dimensions: extern "C" fn(*mut GonkNativeWindow, c_int, c_int) -> c_int,
     api_connect: extern "C" fn(*mut GonkNativeWindow, c_int) -> c_int,
     api_disconnect: extern "C" fn(*mut GonkNativeWindow, c_int) -> c_int,
+    set_buffer_count: extern "C" fn(*mut GonkNativeWindow, c_int) -> c_int,
+    set_scaling_mode: extern "C" fn(*mut GonkNativeWindow, c_int) -> c_int,
+    set_surface_damage: extern "C" fn(*mut GonkNativeWindow, *const hwc_rect, size_t) -> c_int,
     count: i32,
-    alloc_dev: *mut alloc_device,
+    // Shared with the buffers, which can outlive the window.
+    alloc_dev: Arc<AllocDevice>,
     hwc_dev: *mut hwc_composer_device,
     width: i32,
     height: i32,
-    format: c_int,
+    // The size to go back to when the producer resets the dimensions.
+    default_width: i32,
+    default_height: i32,
+    format: PixelFormat,
+    // What alloc_buffers() got from gralloc for `format`.
+    allocated_format: PixelFormat,
+    // The producer's, from set_usage().
     usage: c_int,
-    last_fence: c_int,
+    // The HWC's, added to the producer's when allocating.
+    consumer_usage: c_int,
+    transform: c_int,
+    // The panel orientation producers should pre-rotate for.
+    transform_hint: c_int,
+    last_fence: Fence,
     last_idx: i32,
-    bufs: [Option<*mut GonkNativeWindowBuffer>; 2],
-    fences: [c_int; 2],
+    bufs: Vec<Option<*mut GonkNativeWindowBuffer>>,
+    fences: Vec<Fence>,
+    // Slots whose buffer free_slot() released, left out until realloc_slot().
+    freed: Vec<bool>,
+    // Whether bufs holds a live set of buffers, dequeued or not.
+    allocated: bool,
+    // Bumped by every alloc_buffers(), to spot buffers from an older set.
+    generation: u32,
+    // The part of the buffer to show, set by scaling_mode if None.
+    source_crop: Option<hwc_frect>,
+    // Whether the HWC takes float source crops (1.3+) or integer ones.
+    float_crop: bool,
+    // Whether draw() adds the HWC_SKIP_LAYER layer.
+    skip_layer: bool,
+    // Where to show it on screen, set by scaling_mode if None.
+    display_frame: Option<hwc_rect>,
+    // How the source crop and display frame are picked when not set.
+    scaling_mode: ScalingMode,
+    // Dirty rectangles for the next frame, the whole buffer if empty.
+    damage: Vec<hwc_rect>,
+    stats: FrameStats,
+    // When the current summary period started, and the stats back then.
+    log_summary: Option<(Instant, FrameStats)>,
+    frame_budget: Option<FrameBudget>,
+    // In static mode, the buffer count to go back to when leaving it.
+    static_content: Option<usize>,
+    // Whether the single static buffer was presented since its allocation.
+    static_shown: bool,
+    // Retire fences for the present thread, if someone wants PresentInfo.
+    present_sender: Option<Sender<(usize, Fence, Instant)>>,
+    overlay: Option<Overlay>,
+    // Vsyncs to wait for before presenting, and where they come from.
+    swap_interval: c_int,
+    vsync_events: Option<Receiver<HwcEvent>>,
+    // How the overlay is blended over the window, kept across set_overlay().
+    overlay_plane_alpha: u8,
+    overlay_blending: Blending,
+    composition: CompositionResult,
+    // Filled in by an HWC_BACKGROUND layer below everything, if set.
+    background_color: Option<hwc_color>,
+    // Whether the HWC took the background layer in the last prepare().
+    background_accepted: bool,
+    // The buffers alloc_buffers_async() is allocating.
+    pending_alloc: Option<JoinHandle<Result<BufferSet, GonkGfxError>>>,
+    // The layers of the last set(), if capturing them is enabled.
+    last_layers: Option<Vec<LayerInfo>>,
+    contents: Box<DisplayContents>,
+    // The NATIVE_WINDOW_API_* of the producer, None while there is none.
+    connected_api: Option<c_int>,
+    // Told about every frame sent to the HWC.
+    vsync_waker: Option<VsyncWaker>,
+    // How long dequeue_buffer waits for a free buffer, None to not wait.
+    dequeue_timeout: Option<Duration>,
+    buffer_wait: Arc<BufferWait>,
+    // The slot of the buffer queue_buffer is presenting, left empty until
+    // it is done.
+    presenting: Option<usize>,
 }
 
 impl ANativeBase {
@@ -104,6 +798,14 @@ impl ANativeBase {
 pub struct GonkNativeWindowBuffer {
     buffer: ANativeWindowBuffer,
     count: i32,
+    // What frees the buffer, None for an external one. Holding on to it
+    // keeps the device open for as long as the buffer is around.
+    alloc_dev: Option<Arc<AllocDevice>>,
+    // The GonkNativeWindow::generation it was allocated in.
+    generation: u32,
+    // For a buffer allocated by someone else, the module it is registered
+    // with, to unregister it from rather than freeing it.
+    external: Option<GrallocModule>,
 }
 
 #[link(name = "native_window_glue", kind = "static")]
@@ -111,138 +813,545 @@ extern "C" {
     fn gnw_perform(win: *mut ANativeWindow, op: c_int, ...) -> c_int;
 }
 
+#[cfg(not(feature = "stub-backend"))]
 #[link(name = "suspend")]
 extern "C" {
-    pub fn autosuspend_disable();
-    pub fn autosuspend_enable();
+    pub fn autosuspend_disable() -> c_int;
+    pub fn autosuspend_enable() -> c_int;
+}
+
+#[cfg(feature = "stub-backend")]
+pub use stub::{autosuspend_disable, autosuspend_enable};
+
+// Number of outstanding requests to keep autosuspend disabled.
+pub(crate) static AUTOSUSPEND_HOLDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Enables or disables autosuspend for the whole process.
+///
+/// Calls are reference counted: autosuspend is only enabled again once every
+/// `set_autosuspend(false)` has been balanced by a `set_autosuspend(true)`.
+pub fn set_autosuspend(enabled: bool) {
+    if enabled {
+        loop {
+            let holds = AUTOSUSPEND_HOLDS.load(Ordering::SeqCst);
+            if holds == 0 {
+                warn!(target: LOG_TARGET, "Unbalanced autosuspend enable");
+                return;
+            }
+            if AUTOSUSPEND_HOLDS
+                .compare_exchange(holds, holds - 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                if holds == 1 {
+                    let ret = unsafe { autosuspend_enable() };
+                    info!(target: LOG_TARGET, "autosuspend_enable returned {}", ret);
+                }
+                return;
+            }
+        }
+    } else if AUTOSUSPEND_HOLDS.fetch_add(1, Ordering::SeqCst) == 0 {
+        let ret = unsafe { autosuspend_disable() };
+        info!(target: LOG_TARGET, "autosuspend_disable returned {}", ret);
+    }
+}
+
+/// Keeps autosuspend disabled while alive.
+pub struct AutosuspendGuard;
+
+impl AutosuspendGuard {
+    pub fn new() -> AutosuspendGuard {
+        set_autosuspend(false);
+        AutosuspendGuard
+    }
+}
+
+impl Drop for AutosuspendGuard {
+    fn drop(&mut self) {
+        set_autosuspend(true);
+    }
+}
+
+/// Returns the `ANativeWindow` at the start of a `GonkNativeWindow`, to be
+/// passed to `eglCreateWindowSurface` as its native window.
+///
+/// The caller must keep the window alive for as long as the EGL surface uses
+/// it, e.g. by holding a reference taken with `inc_ref`.
+pub fn native_window_ptr(win: *mut GonkNativeWindow) -> *mut ANativeWindow {
+    win as *mut ANativeWindow
+}
+
+/// Returns the `ANativeWindowBuffer` at the start of a window buffer, e.g. to
+/// pass to `eglCreateImageKHR` with `EGL_NATIVE_BUFFER_ANDROID`.
+pub fn buffer_native(buf: *mut GonkNativeWindowBuffer) -> *mut ANativeWindowBuffer {
+    buf as *mut ANativeWindowBuffer
 }
 
-extern "C" fn set_swap_interval(_base: *mut ANativeWindow, _interval: c_int) -> c_int {
-    debug!("set_swap_interval");
+/// Returns the gralloc handle of a window buffer. The handle is owned by the
+/// buffer and freed with it, so it must not be used past the buffer's last
+/// reference. Null for a null buffer.
+pub fn buffer_handle(buf: *const GonkNativeWindowBuffer) -> *const native_handle {
+    if buf.is_null() {
+        return ptr::null();
+    }
+    unsafe { (*buf).buffer.handle }
+}
+
+extern "C" fn set_swap_interval(base: *mut ANativeWindow, interval: c_int) -> c_int {
+    trace!(target: LOG_TARGET, "set_swap_interval {}", interval);
+    unsafe {
+        let window: &mut GonkNativeWindow = transmute(base);
+        window.set_swap_interval(interval);
+    }
     0
 }
 
+// Query codes, from system/core/include/system/window.h
+
 const NATIVE_WINDOW_WIDTH: c_int = 0;
 const NATIVE_WINDOW_HEIGHT: c_int = 1;
 const NATIVE_WINDOW_FORMAT: c_int = 2;
-const NATIVE_WINDOW_SET_CROP: c_int = 3;
+// Number of buffers the consumer keeps, that can't be dequeued at once.
+const NATIVE_WINDOW_MIN_UNDEQUEUED_BUFFERS: c_int = 3;
+// Whether queued buffers go to the window composer (1) or not (0).
+const NATIVE_WINDOW_QUEUES_TO_WINDOW_COMPOSER: c_int = 4;
+// One of the NATIVE_WINDOW_FRAMEBUFFER/SURFACE concrete types below.
+const NATIVE_WINDOW_CONCRETE_TYPE: c_int = 5;
 const NATIVE_WINDOW_DEFAULT_WIDTH: c_int = 6;
 const NATIVE_WINDOW_DEFAULT_HEIGHT: c_int = 7;
 const NATIVE_WINDOW_TRANSFORM_HINT: c_int = 8;
 const NATIVE_WINDOW_CONSUMER_USAGE_BITS: c_int = 10;
 const NATIVE_WINDOW_DEFAULT_DATASPACE: c_int = 12;
 const NATIVE_WINDOW_BUFFER_AGE: c_int = 13;
+// How long the last dequeue_buffer and queue_buffer took, in microseconds.
+const NATIVE_WINDOW_LAST_DEQUEUE_DURATION: c_int = 14;
+const NATIVE_WINDOW_LAST_QUEUE_DURATION: c_int = 15;
 
-extern "C" fn query(base: *const ANativeWindow, what: c_int, value: *mut c_int) -> c_int {
-    info!("query {}", what);
-    unsafe {
-        let window: &GonkNativeWindow = transmute(base);
+// Values for NATIVE_WINDOW_CONCRETE_TYPE.
+#[allow(dead_code)]
+const NATIVE_WINDOW_FRAMEBUFFER: c_int = 0;
+const NATIVE_WINDOW_SURFACE: c_int = 1;
 
-        match what {
-            NATIVE_WINDOW_WIDTH => {
-                *value = window.width;
-                0
-            }
-            NATIVE_WINDOW_HEIGHT => {
-                *value = window.height;
-                0
-            }
-            NATIVE_WINDOW_FORMAT => {
-                *value = window.format;
-                0
-            }
-            NATIVE_WINDOW_SET_CROP => {
-                // TODO: save for later use?
-                0
-            }
+// Producer APIs, from system/core/include/system/window.h
 
-            NATIVE_WINDOW_DEFAULT_WIDTH => {
-                *value = window.width;
-                0
+/// Rendering with EGL and GLES.
+pub const NATIVE_WINDOW_API_EGL: c_int = 1;
+/// Drawing with the CPU.
+pub const NATIVE_WINDOW_API_CPU: c_int = 2;
+/// Decoded video.
+pub const NATIVE_WINDOW_API_MEDIA: c_int = 3;
+pub const NATIVE_WINDOW_API_CAMERA: c_int = 4;
+
+// Scaling modes, from system/core/include/system/window.h
+
+/// Only show buffers matching the window size, keep the last one otherwise.
+pub const NATIVE_WINDOW_SCALING_MODE_FREEZE: c_int = 0;
+/// Stretch the buffer to fill the window.
+pub const NATIVE_WINDOW_SCALING_MODE_SCALE_TO_WINDOW: c_int = 1;
+/// Scale the buffer to fill the window keeping its aspect ratio, cropping
+/// the center of the buffer.
+pub const NATIVE_WINDOW_SCALING_MODE_SCALE_CROP: c_int = 2;
+/// Show the buffer unscaled, cropped to the window.
+pub const NATIVE_WINDOW_SCALING_MODE_NO_SCALE_CROP: c_int = 3;
+
+/// How buffers that don't match the window size are shown, the window size
+/// being its default size (usually the display's).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScalingMode {
+    Freeze,
+    ScaleToWindow,
+    ScaleCrop,
+    NoScaleCrop,
+}
+
+impl ScalingMode {
+    pub fn from_native(mode: c_int) -> Option<ScalingMode> {
+        match mode {
+            NATIVE_WINDOW_SCALING_MODE_FREEZE => Some(ScalingMode::Freeze),
+            NATIVE_WINDOW_SCALING_MODE_SCALE_TO_WINDOW => Some(ScalingMode::ScaleToWindow),
+            NATIVE_WINDOW_SCALING_MODE_SCALE_CROP => Some(ScalingMode::ScaleCrop),
+            NATIVE_WINDOW_SCALING_MODE_NO_SCALE_CROP => Some(ScalingMode::NoScaleCrop),
+            _ => None,
+        }
+    }
+
+    pub fn as_native(&self) -> c_int {
+        match *self {
+            ScalingMode::Freeze => NATIVE_WINDOW_SCALING_MODE_FREEZE,
+            ScalingMode::ScaleToWindow => NATIVE_WINDOW_SCALING_MODE_SCALE_TO_WINDOW,
+            ScalingMode::ScaleCrop => NATIVE_WINDOW_SCALING_MODE_SCALE_CROP,
+            ScalingMode::NoScaleCrop => NATIVE_WINDOW_SCALING_MODE_NO_SCALE_CROP,
+        }
+    }
+
+    // The source crop and display frame showing a buf_w x buf_h buffer in a
+    // win_w x win_h window, or None if the buffer shouldn't be shown.
+    fn geometry(
+        &self,
+        buf_w: i32,
+        buf_h: i32,
+        win_w: i32,
+        win_h: i32,
+    ) -> Option<(hwc_frect, hwc_rect)> {
+        let whole_window = hwc_rect {
+            left: 0,
+            top: 0,
+            right: win_w,
+            bottom: win_h,
+        };
+        let whole_buffer = hwc_frect {
+            left: 0.0,
+            top: 0.0,
+            right: buf_w as f32,
+            bottom: buf_h as f32,
+        };
+        match *self {
+            ScalingMode::Freeze => {
+                if buf_w != win_w || buf_h != win_h {
+                    return None;
+                }
+                Some((whole_buffer, whole_window))
             }
-            NATIVE_WINDOW_DEFAULT_HEIGHT => {
-                *value = window.height;
-                0
+            ScalingMode::ScaleToWindow => Some((whole_buffer, whole_window)),
+            ScalingMode::ScaleCrop => {
+                // Keep the largest centered part of the buffer with the
+                // window's aspect ratio.
+                let (buf_w, buf_h) = (buf_w as f32, buf_h as f32);
+                let scale = (win_w as f32 / buf_w).max(win_h as f32 / buf_h);
+                let crop_w = win_w as f32 / scale;
+                let crop_h = win_h as f32 / scale;
+                let left = (buf_w - crop_w) / 2.0;
+                let top = (buf_h - crop_h) / 2.0;
+                let crop = hwc_frect {
+                    left: left,
+                    top: top,
+                    right: left + crop_w,
+                    bottom: top + crop_h,
+                };
+                Some((crop, whole_window))
             }
-            NATIVE_WINDOW_TRANSFORM_HINT => {
-                *value = 0;
-                0
+            ScalingMode::NoScaleCrop => {
+                let w = buf_w.min(win_w);
+                let h = buf_h.min(win_h);
+                let crop = hwc_frect {
+                    left: 0.0,
+                    top: 0.0,
+                    right: w as f32,
+                    bottom: h as f32,
+                };
+                let frame = hwc_rect {
+                    left: 0,
+                    top: 0,
+                    right: w,
+                    bottom: h,
+                };
+                Some((crop, frame))
             }
-            NATIVE_WINDOW_CONSUMER_USAGE_BITS => {
-                *value = window.usage;
-                0
+        }
+    }
+}
+
+extern "C" fn query(base: *const ANativeWindow, what: c_int, value: *mut c_int) -> c_int {
+    trace!(target: LOG_TARGET, "query {}", what);
+    if value.is_null() {
+        error!(target: LOG_TARGET, "Null value pointer for query {}", what);
+        return -EINVAL;
+    }
+    unsafe {
+        let window: &GonkNativeWindow = transmute(base);
+
+        let result = match what {
+            NATIVE_WINDOW_WIDTH => dimension(window.width),
+            NATIVE_WINDOW_HEIGHT => dimension(window.height),
+            NATIVE_WINDOW_FORMAT => Some(window.allocated_format.as_gralloc()),
+            // The buffer last sent to the HWC is never handed out.
+            NATIVE_WINDOW_MIN_UNDEQUEUED_BUFFERS => Some(window.slot_count() as c_int - 1),
+            NATIVE_WINDOW_QUEUES_TO_WINDOW_COMPOSER => Some(1),
+            NATIVE_WINDOW_CONCRETE_TYPE => Some(NATIVE_WINDOW_SURFACE),
+            NATIVE_WINDOW_DEFAULT_WIDTH => dimension(window.default_width),
+            NATIVE_WINDOW_DEFAULT_HEIGHT => dimension(window.default_height),
+            NATIVE_WINDOW_TRANSFORM_HINT => Some(window.transform_hint),
+            NATIVE_WINDOW_CONSUMER_USAGE_BITS => Some(window.consumer_usage),
+            NATIVE_WINDOW_DEFAULT_DATASPACE => Some(0),
+            NATIVE_WINDOW_BUFFER_AGE => Some(0),
+            NATIVE_WINDOW_LAST_DEQUEUE_DURATION => {
+                Some(duration_us(window.stats().last_dequeue_ns))
             }
-            NATIVE_WINDOW_DEFAULT_DATASPACE => {
-                *value = 0;
-                0
+            NATIVE_WINDOW_LAST_QUEUE_DURATION => Some(duration_us(window.stats().last_queue_ns)),
+            _ => {
+                error!(target: LOG_TARGET, "Unsupported query: {}", what);
+                return -1;
             }
-            NATIVE_WINDOW_BUFFER_AGE => {
-                *value = 0;
+        };
+        match result {
+            Some(result) => {
+                *value = result;
                 0
             }
-            _ => {
-                error!("Unsupported query: {}", what);
-                -1
+            None => {
+                // Some EGL drivers abort on a 0x0 window. -ENODEV is Android's
+                // NO_INIT, which they handle.
+                error!(target: LOG_TARGET, "Query {} before the window has a size", what);
+                -ENODEV
             }
         }
     }
 }
 
+// A FrameStats duration for query(), saturating as window.h doesn't say.
+fn duration_us(ns: u64) -> c_int {
+    (ns / 1000).min(c_int::max_value() as u64) as c_int
+}
+
+// A width or height for query(), None while unset.
+fn dimension(size: i32) -> Option<c_int> {
+    if size > 0 {
+        Some(size)
+    } else {
+        None
+    }
+}
+
 extern "C" fn dequeue_buffer(
     base: *mut ANativeWindow,
     buf: *mut *mut ANativeWindowBuffer,
     fence: *mut c_int,
 ) -> c_int {
-    info!("dequeue_buffer");
+    trace!(target: LOG_TARGET, "dequeue_buffer");
+    status(dequeue_or_wait(base, buf, fence))
+}
+
+// Waits for a buffer to be queued or canceled while the producer holds all
+// of them, for as long as the dequeue timeout allows. Only running out of
+// buffers does, not e.g. gralloc failing to allocate them.
+fn dequeue_or_wait(
+    base: *mut ANativeWindow,
+    buf: *mut *mut ANativeWindowBuffer,
+    fence: *mut c_int,
+) -> Result<(), GonkGfxError> {
+    let start = Instant::now();
+    let window: &mut GonkNativeWindow = unsafe { transmute(base) };
+    let wait = window.buffer_wait.clone();
+    let mut presenting = None;
+    let mut guard = wait.lock.lock().unwrap();
+    let deadline = window.dequeue_timeout.map(|timeout| start + timeout);
+    let result = loop {
+        // Allocating releases the buffers and the layers a present in flight
+        // still uses: wait for it to be done, taking the locks in the order
+        // queue_buffer does.
+        if presenting.is_none() && window.alloc_pending() {
+            drop(guard);
+            presenting = Some(wait.presenting.lock().unwrap());
+            guard = wait.lock.lock().unwrap();
+        }
+        match do_dequeue_buffer(base, buf, fence) {
+            Err(GonkGfxError::NoBufferAvailable) => (),
+            result => break result,
+        }
+        let now = Instant::now();
+        match deadline {
+            Some(deadline) if now < deadline => {
+                // Lets queue_buffer release a buffer meanwhile.
+                drop(presenting.take());
+                guard = wait.released.wait_timeout(guard, deadline - now).unwrap().0;
+            }
+            Some(_) => {
+                error!(target: LOG_TARGET, "Timed out waiting for a buffer to dequeue");
+                break Err(GonkGfxError::TimedOut);
+            }
+            None => break Err(GonkGfxError::NoBufferAvailable),
+        }
+    };
+    window.stats.last_dequeue_ns = duration_ns(start.elapsed());
+    if result.is_ok() {
+        window.stats.dequeued += 1;
+    }
+    result
+}
+
+fn do_dequeue_buffer(
+    base: *mut ANativeWindow,
+    buf: *mut *mut ANativeWindowBuffer,
+    fence: *mut c_int,
+) -> Result<(), GonkGfxError> {
     unsafe {
         let window: &mut GonkNativeWindow = transmute(base);
-        debug!(
+        if window.connected_api.is_none() {
+            error!(target: LOG_TARGET, "Dequeue without a connected producer");
+            return Err(GonkGfxError::NotConnected);
+        }
+        window.finish_alloc()?;
+        // A reallocation may have failed since connect().
+        if !window.allocated {
+            window.alloc_buffers()?;
+        }
+        trace!(
+            target: LOG_TARGET,
             "We have {} buffers, last_idx={}",
             window.bufs.len(),
             window.last_idx
         );
         for idx in 0..window.bufs.len() {
-            if idx == window.last_idx as usize {
+            // The buffer on screen is only drawn into again in static mode.
+            if idx == window.last_idx as usize && window.static_content.is_none() {
                 continue;
             }
             match window.bufs[idx] {
                 Some(entry) => {
-                    debug!("Buffer {} exists", idx);
+                    trace!(target: LOG_TARGET, "Buffer {} exists", idx);
                     (*buf) = transmute(entry);
                     window.bufs[idx] = None;
-                    *fence = window.fences[idx];
-                    window.fences[idx] = -1;
-                    return 0;
+                    *fence = replace(&mut window.fences[idx], Fence::none()).into_raw();
+                    return Ok(());
                 }
-                None => debug!("Buffer {} is None", idx),
+                None => trace!(target: LOG_TARGET, "Buffer {} is None", idx),
             }
         }
     }
-    error!("returning -1!!");
-    -1
+    error!(target: LOG_TARGET, "No buffer left to dequeue");
+    Err(GonkGfxError::NoBufferAvailable)
 }
 
-extern "C" fn queue_buffer(
-    base: *mut ANativeWindow,
-    buf: *mut ANativeWindowBuffer,
-    fence: c_int,
+// A buffer the producer dequeued before the window reallocated its buffers
+// (e.g. on set_dimensions) has no slot anymore. We drop the reference the
+// producer got at dequeue time instead of taking it back. Its fence is the
+// caller's to drop.
+fn reject_stale_buffer(window: &GonkNativeWindow, buf: *mut ANativeWindowBuffer) -> bool {
+    let gonkbuf = buf as *mut GonkNativeWindowBuffer;
+    unsafe {
+        if (*gonkbuf).generation == window.generation {
+            return false;
+        }
+        error!(
+            target: LOG_TARGET,
+            "Rejecting a {}x{} buffer dequeued before the buffers were reallocated",
+            (*buf).width,
+            (*buf).height
+        );
+        ((*buf).common.dec_ref)(&mut (*buf).common);
+    }
+    true
+}
+
+extern "C" fn queue_buffer(
+    base: *mut ANativeWindow,
+    buf: *mut ANativeWindowBuffer,
+    fence: c_int,
 ) -> c_int {
-    info!("queue_buffer");
+    trace!(target: LOG_TARGET, "queue_buffer");
+    let start = Instant::now();
+    let window: &mut GonkNativeWindow = unsafe { transmute(base) };
+    let wait = window.buffer_wait.clone();
+    let ret = {
+        let _presenting = wait.presenting.lock().unwrap();
+        do_queue_buffer(base, buf, Fence::from_raw(fence), &wait.lock, start)
+    };
+    // The buffer on screen until now can be dequeued again.
+    wait.released.notify_all();
+    ret
+}
+
+// Takes the buffer lock to find the buffer a slot, and to put it there
+// once presented, but not while presenting. The time since `start` is
+// recorded with the lock held, like the rest of the stats.
+fn do_queue_buffer(
+    base: *mut ANativeWindow,
+    buf: *mut ANativeWindowBuffer,
+    fence: Fence,
+    lock: &Mutex<()>,
+    start: Instant,
+) -> c_int {
+    let window: &mut GonkNativeWindow = unsafe { transmute(base) };
+    let (idx, fence) = {
+        let _guard = lock.lock().unwrap();
+        match queue_slot(base, buf, fence) {
+            Ok(Some(queued)) => queued,
+            done => {
+                window.stats.last_queue_ns = duration_ns(start.elapsed());
+                return match done {
+                    Err(status) => status,
+                    _ => 0,
+                };
+            }
+        }
+    };
+    if let Some(ref waker) = window.vsync_waker {
+        waker.frame_queued();
+    }
+    let result = window.draw(idx, buf, fence);
+    let _guard = lock.lock().unwrap();
+    window.presenting = None;
+    window.stats.last_queue_ns = duration_ns(start.elapsed());
+    // The buffers may have been reallocated meanwhile.
+    if reject_stale_buffer(window, buf) {
+        return status(result.map(|_| ()));
+    }
+    window.bufs[idx] = Some(unsafe { transmute(buf) });
+    match result {
+        Ok(fence) => {
+            window.fences[idx] = fence;
+            window.static_shown = true;
+            0
+        }
+        // The buffer is back in its slot, and the previous frame still on
+        // screen.
+        Err(err) => err.status(),
+    }
+}
+
+// Finds the queued buffer a slot. Returns the slot and the fence to present
+// it with, leaving the slot empty, or None if there is nothing to present.
+fn queue_slot(
+    base: *mut ANativeWindow,
+    buf: *mut ANativeWindowBuffer,
+    fence: Fence,
+) -> Result<Option<(usize, Fence)>, c_int> {
     unsafe {
         let window: &mut GonkNativeWindow = transmute(base);
+        if reject_stale_buffer(window, buf) {
+            return Err(GonkGfxError::InvalidArgument.status());
+        }
+        if window.connected_api.is_none() {
+            // Keep the buffer for the next producer, without showing it.
+            error!(target: LOG_TARGET, "Queue without a connected producer");
+            do_cancel_buffer(base, buf, fence);
+            return Err(GonkGfxError::NotConnected.status());
+        }
+        // A buffer of the current set always has the window's size, unless
+        // the size changed without a reallocation. Trust the buffer then.
+        if (*buf).width != window.width || (*buf).height != window.height {
+            info!(
+                target: LOG_TARGET,
+                "Adopting the {}x{} size of the queued buffer",
+                (*buf).width,
+                (*buf).height
+            );
+            window.width = (*buf).width;
+            window.height = (*buf).height;
+        }
         for idx in 0..window.bufs.len() {
             match window.bufs[idx] {
                 Some(_) => (),
+                None if window.freed[idx] => (),
                 None => {
                     window.last_idx = idx as i32;
-                    window.bufs[idx] = Some(transmute(buf));
-                    window.fences[idx] = window.draw(buf, fence);
-                    return 0;
+                    if window.static_content.is_some()
+                        && window.static_shown
+                        && window.damage.is_empty()
+                    {
+                        // The HWC already shows this very buffer.
+                        trace!(target: LOG_TARGET, "Nothing changed, not presenting");
+                        window.bufs[idx] = Some(transmute(buf));
+                        window.stats.skipped_frames += 1;
+                        window.fences[idx] = fence;
+                        return Ok(None);
+                    }
+                    window.presenting = Some(idx);
+                    return Ok(Some((idx, fence)));
                 }
             }
         }
     }
-    -1
+    Err(-1)
 }
 
 extern "C" fn cancel_buffer(
@@ -250,16 +1359,35 @@ extern "C" fn cancel_buffer(
     buf: *mut ANativeWindowBuffer,
     fence: c_int,
 ) -> c_int {
-    info!("cancel_buffer");
+    trace!(target: LOG_TARGET, "cancel_buffer");
+    let window: &mut GonkNativeWindow = unsafe { transmute(base) };
+    let wait = window.buffer_wait.clone();
+    let ret = {
+        let _guard = wait.lock.lock().unwrap();
+        do_cancel_buffer(base, buf, Fence::from_raw(fence))
+    };
+    wait.released.notify_all();
+    ret
+}
+
+fn do_cancel_buffer(
+    base: *mut ANativeWindow,
+    buf: *mut ANativeWindowBuffer,
+    // Not waited on, the buffer wasn't drawn to. Dropping it closes it.
+    _fence: Fence,
+) -> c_int {
     unsafe {
         let window: &mut GonkNativeWindow = transmute(base);
+        if reject_stale_buffer(window, buf) {
+            return 0;
+        }
         for idx in 0..window.bufs.len() {
             match window.bufs[idx] {
                 Some(_) => (),
+                None if window.freed[idx] || window.presenting == Some(idx) => (),
                 None => {
                     window.bufs[idx] = Some(transmute(buf));
-                    window.fences[idx] = -1;
-                    close(fence);
+                    window.fences[idx] = Fence::none();
                     return 0;
                 }
             }
@@ -268,51 +1396,153 @@ extern "C" fn cancel_buffer(
     -1
 }
 
+// Turns a result into the status code the C callbacks return.
+fn status(result: Result<(), GonkGfxError>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => err.status(),
+    }
+}
+
 extern "C" fn set_usage(window: *mut GonkNativeWindow, usage: c_int) -> c_int {
-    info!("Setting usage flags to {}", usage);
+    info!(target: LOG_TARGET, "Setting usage flags to {}", usage);
     unsafe {
+        if (*window).usage == usage {
+            return 0;
+        }
         (*window).usage = usage;
-        (*window).alloc_buffers();
+        status((*window).realloc_buffers())
     }
-    0
 }
 
 extern "C" fn set_format(window: *mut GonkNativeWindow, format: c_int) -> c_int {
-    info!("Setting format to {}", format);
+    info!(target: LOG_TARGET, "Setting format to {}", format);
+    unsafe { status((*window).set_pixel_format(PixelFormat::from_gralloc(format))) }
+}
+
+extern "C" fn set_transform(window: *mut GonkNativeWindow, transform: c_int) -> c_int {
+    info!(target: LOG_TARGET, "set_transform to {}", transform);
     unsafe {
-        (*window).format = format;
+        (*window).transform = transform;
     }
     0
 }
 
-extern "C" fn set_transform(_: *mut GonkNativeWindow, _: c_int) -> c_int {
-    info!("set_transform");
+extern "C" fn set_dimensions(window: *mut GonkNativeWindow, width: c_int, height: c_int) -> c_int {
+    info!(target: LOG_TARGET, "set_dimensions to {}x{}", width, height);
+    if width < 0 || height < 0 || (width == 0) != (height == 0) {
+        error!(target: LOG_TARGET, "Invalid dimensions {}x{}", width, height);
+        return -EINVAL;
+    }
+    unsafe {
+        let window = &mut *window;
+        // 0x0 restores the default size.
+        let (width, height) = if width == 0 {
+            (window.default_width, window.default_height)
+        } else {
+            (width, height)
+        };
+        if width != window.width || height != window.height {
+            window.width = width;
+            window.height = height;
+            return status(window.realloc_buffers());
+        }
+    }
     0
 }
 
-extern "C" fn set_dimensions(_: *mut GonkNativeWindow, width: c_int, height: c_int) -> c_int {
-    info!("set_dimensions to {}x{}", width, height);
-    0
+extern "C" fn set_buffer_count(window: *mut GonkNativeWindow, count: c_int) -> c_int {
+    info!(target: LOG_TARGET, "set_buffer_count to {}", count);
+    if count < 0 {
+        return -EINVAL;
+    }
+    // 0 restores the default count, and we need at least one buffer to draw
+    // while the producer renders into the other.
+    let count = if count == 0 {
+        DEFAULT_BUFFER_COUNT
+    } else {
+        count as usize
+    };
+    if count < 2 {
+        error!(target: LOG_TARGET, "Need at least 2 buffers, got {}", count);
+        return -EINVAL;
+    }
+    unsafe {
+        let window = &mut *window;
+        if window.static_content.is_some() {
+            // Keep the single buffer, and use this count once we leave.
+            window.static_content = Some(count);
+            return 0;
+        }
+        status(window.resize_buffer_set(count))
+    }
 }
 
-extern "C" fn api_connect(_window: *mut GonkNativeWindow, _api: c_int) -> c_int {
-    info!("api_connect");
-    0
+extern "C" fn set_scaling_mode(window: *mut GonkNativeWindow, mode: c_int) -> c_int {
+    info!(target: LOG_TARGET, "set_scaling_mode to {}", mode);
+    match ScalingMode::from_native(mode) {
+        Some(mode) => {
+            unsafe {
+                (*window).scaling_mode = mode;
+            }
+            0
+        }
+        None => {
+            error!(target: LOG_TARGET, "Unknown scaling mode {}", mode);
+            -EINVAL
+        }
+    }
 }
 
-extern "C" fn api_disconnect(_window: *mut GonkNativeWindow, _api: c_int) -> c_int {
-    info!("api_disconnect");
+// What eglSwapBuffersWithDamageKHR and eglSetDamageRegionKHR end up in.
+// EGL gives the rects a bottom-left origin, so their top is below their
+// bottom: flip them to the top-left origin the HWC uses.
+extern "C" fn set_surface_damage(
+    window: *mut GonkNativeWindow,
+    rects: *const hwc_rect,
+    count: size_t,
+) -> c_int {
+    trace!(target: LOG_TARGET, "set_surface_damage with {} rects", count);
+    let window = unsafe { &mut *window };
+    let rects: &[hwc_rect] = if rects.is_null() {
+        &[]
+    } else {
+        unsafe { slice::from_raw_parts(rects, count) }
+    };
+    let height = window.height;
+    let flipped: Vec<hwc_rect> = rects
+        .iter()
+        .map(|rect| {
+            hwc_rect::new(
+                rect.left,
+                height - rect.top,
+                rect.right,
+                height - rect.bottom,
+            )
+        })
+        .collect();
+    window.set_surface_damage(&flipped);
     0
 }
 
+extern "C" fn api_connect(window: *mut GonkNativeWindow, api: c_int) -> c_int {
+    info!(target: LOG_TARGET, "api_connect {}", api);
+    unsafe { status((*window).connect(api)) }
+}
+
+extern "C" fn api_disconnect(window: *mut GonkNativeWindow, api: c_int) -> c_int {
+    info!(target: LOG_TARGET, "api_disconnect {}", api);
+    unsafe { status((*window).disconnect(api)) }
+}
+
 extern "C" fn gnw_inc_ref(base: *mut ANativeBase) {
-    info!("gnw_inc_ref");
+    debug!(target: LOG_TARGET, "gnw_inc_ref");
     let win: &mut GonkNativeWindow = unsafe { transmute(base) };
     win.count += 1;
 }
 
 extern "C" fn gnw_dec_ref(base: *mut ANativeBase) {
-    info!("gnw_dec_ref");
+    debug!(target: LOG_TARGET, "gnw_dec_ref");
     let win: &mut GonkNativeWindow = unsafe { transmute(base) };
     win.count -= 1;
     if win.count == 0 {
@@ -320,14 +1550,126 @@ extern "C" fn gnw_dec_ref(base: *mut ANativeBase) {
     }
 }
 
-impl GonkNativeWindow {
-    pub fn new(
-        hwc_dev: *mut hwc_composer_device,
-        width: i32,
-        height: i32,
-        usage: c_int,
-    ) -> *mut GonkNativeWindow {
-        let alloc_dev = get_gralloc_module();
+/// Configures and creates a `GonkNativeWindow`.
+pub struct GonkNativeWindowBuilder {
+    alloc_dev: Option<AllocDevice>,
+    hwc_dev: *mut hwc_composer_device,
+    width: i32,
+    height: i32,
+    usage: c_int,
+    consumer_usage: c_int,
+    format: PixelFormat,
+    buffer_count: usize,
+    transform: c_int,
+    skip_layer: bool,
+}
+
+impl GonkNativeWindowBuilder {
+    pub fn new() -> GonkNativeWindowBuilder {
+        GonkNativeWindowBuilder {
+            alloc_dev: None,
+            hwc_dev: ptr::null_mut(),
+            width: 0,
+            height: 0,
+            usage: 0,
+            consumer_usage: DEFAULT_CONSUMER_USAGE,
+            format: PixelFormat::Rgba8888,
+            buffer_count: DEFAULT_BUFFER_COUNT,
+            transform: 0,
+            skip_layer: true,
+        }
+    }
+
+    /// The gralloc device to allocate buffers from. One is opened if unset.
+    pub fn alloc_device(mut self, alloc_dev: AllocDevice) -> GonkNativeWindowBuilder {
+        self.alloc_dev = Some(alloc_dev);
+        self
+    }
+
+    /// The HWC device to present buffers with. This one is mandatory.
+    pub fn hwc_device(mut self, hwc_dev: *mut hwc_composer_device) -> GonkNativeWindowBuilder {
+        self.hwc_dev = hwc_dev;
+        self
+    }
+
+    pub fn dimensions(mut self, width: i32, height: i32) -> GonkNativeWindowBuilder {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// The producer's usage, until it calls set_usage().
+    pub fn usage(mut self, usage: c_int) -> GonkNativeWindowBuilder {
+        self.usage = usage;
+        self
+    }
+
+    /// The usage the HWC needs, added to the producer's when allocating and
+    /// reported as the consumer usage bits. GRALLOC_USAGE_HW_COMPOSER and
+    /// GRALLOC_USAGE_HW_FB by default, as some display controllers won't
+    /// scan out buffers without the latter.
+    pub fn consumer_usage(mut self, usage: c_int) -> GonkNativeWindowBuilder {
+        self.consumer_usage = usage;
+        self
+    }
+
+    /// The format of the buffers until the producer calls set_format().
+    /// RGBA8888 by default.
+    pub fn format(mut self, format: PixelFormat) -> GonkNativeWindowBuilder {
+        self.format = format;
+        self
+    }
+
+    pub fn buffer_count(mut self, buffer_count: usize) -> GonkNativeWindowBuilder {
+        self.buffer_count = buffer_count;
+        self
+    }
+
+    pub fn transform(mut self, transform: c_int) -> GonkNativeWindowBuilder {
+        self.transform = transform;
+        self
+    }
+
+    /// Whether to send the HWC an HWC_SKIP_LAYER framebuffer layer below the
+    /// window's, as by default. Some HWCs compose the whole frame with GL
+    /// when they see it, while the framebuffer target alone would do for a
+    /// single fullscreen window. Others need it, so compare
+    /// `GonkNativeWindow::composition` with and without it.
+    pub fn skip_layer(mut self, skip_layer: bool) -> GonkNativeWindowBuilder {
+        self.skip_layer = skip_layer;
+        self
+    }
+
+    /// Creates the window. Fails with `InvalidArgument` without an HWC
+    /// device or with less than 2 buffers, with `Unsupported` for an HWC2
+    /// device, and with gralloc's error if no gralloc device was set and
+    /// none can be opened.
+    pub fn build(self) -> Result<*mut GonkNativeWindow, GonkGfxError> {
+        if self.hwc_dev.is_null() {
+            error!(target: LOG_TARGET, "No HWC device set");
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        if self.buffer_count < 2 {
+            error!(
+                target: LOG_TARGET,
+                "Need at least 2 buffers, not {}", self.buffer_count
+            );
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        let version = unsafe { (*self.hwc_dev).common.version };
+        if HwcApiVersion::major(version) >= 2 {
+            error!(
+                target: LOG_TARGET,
+                "HWC2 device (version {:x}), only HWC 1.x is supported", version
+            );
+            return Err(GonkGfxError::Unsupported);
+        }
+        let version = HwcApiVersion::from(version);
+        let float_crop = version.map_or(true, |version| version.has_float_source_crop());
+        let alloc_dev = match self.alloc_dev {
+            Some(alloc_dev) => Arc::new(alloc_dev),
+            None => Arc::new(AllocDevice::new()?),
+        };
         let window = Box::new(GonkNativeWindow {
             window: ANativeWindow {
                 common: ANativeBase {
@@ -339,7 +1681,7 @@ impl GonkNativeWindow {
                 },
                 flags: 0,
                 min_swap_interval: 0,
-                max_swap_interval: 0,
+                max_swap_interval: MAX_SWAP_INTERVAL,
                 xdpi: 0f32,
                 ydpi: 0f32,
                 oem: unsafe { zeroed() },
@@ -360,134 +1702,1178 @@ impl GonkNativeWindow {
             set_dimensions: set_dimensions,
             api_connect: api_connect,
             api_disconnect: api_disconnect,
+            set_buffer_count: set_buffer_count,
+            set_scaling_mode: set_scaling_mode,
+            set_surface_damage: set_surface_damage,
             count: 1,
             alloc_dev: alloc_dev,
-            hwc_dev: hwc_dev,
-            width: width,
-            height: height,
-            format: 0,
-            usage: usage,
-            last_fence: -1,
+            hwc_dev: self.hwc_dev,
+            width: self.width,
+            height: self.height,
+            default_width: self.width,
+            default_height: self.height,
+            format: self.format,
+            allocated_format: self.format,
+            usage: self.usage,
+            consumer_usage: self.consumer_usage,
+            transform: self.transform,
+            transform_hint: display_transform_hint(HWC_DISPLAY_PRIMARY),
+            last_fence: Fence::none(),
             last_idx: -1,
-            bufs: unsafe { zeroed() },
-            fences: [-1, -1],
+            bufs: vec![None; self.buffer_count],
+            freed: vec![false; self.buffer_count],
+            fences: (0..self.buffer_count).map(|_| Fence::none()).collect(),
+            allocated: false,
+            generation: 0,
+            source_crop: None,
+            float_crop: float_crop,
+            skip_layer: self.skip_layer,
+            display_frame: None,
+            scaling_mode: ScalingMode::NoScaleCrop,
+            damage: Vec::new(),
+            stats: FrameStats::default(),
+            log_summary: None,
+            frame_budget: None,
+            static_content: None,
+            static_shown: false,
+            present_sender: None,
+            overlay: None,
+            swap_interval: 1,
+            vsync_events: None,
+            overlay_plane_alpha: 0xff,
+            overlay_blending: Blending::None,
+            composition: CompositionResult::default(),
+            background_color: None,
+            background_accepted: false,
+            pending_alloc: None,
+            last_layers: None,
+            connected_api: None,
+            vsync_waker: None,
+            dequeue_timeout: None,
+            buffer_wait: Arc::new(BufferWait::default()),
+            presenting: None,
+            contents: DisplayContents::new(),
+        });
+
+        Ok(unsafe { transmute(window) })
+    }
+}
+
+impl GonkNativeWindow {
+    /// A window with the builder's defaults otherwise, failing as `build`
+    /// does.
+    pub fn new(
+        hwc_dev: *mut hwc_composer_device,
+        width: i32,
+        height: i32,
+        usage: c_int,
+    ) -> Result<*mut GonkNativeWindow, GonkGfxError> {
+        GonkNativeWindowBuilder::new()
+            .hwc_device(hwc_dev)
+            .dimensions(width, height)
+            .usage(usage)
+            .build()
+    }
+
+    /// Sets the part of the buffer shown by the HWC, which must lie within
+    /// the window's buffer dimensions.
+    pub fn set_source_crop(&mut self, crop: hwc_frect) -> Result<(), GonkGfxError> {
+        if crop.left < 0.0
+            || crop.top < 0.0
+            || crop.left >= crop.right
+            || crop.top >= crop.bottom
+            || crop.right > self.width as f32
+            || crop.bottom > self.height as f32
+        {
+            error!(
+                target: LOG_TARGET,
+                "Invalid source crop for a {}x{} window",
+                self.width, self.height
+            );
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        self.source_crop = Some(crop);
+        Ok(())
+    }
+
+    /// Sets the screen rectangle the source crop is scaled into.
+    pub fn set_display_frame(&mut self, frame: hwc_rect) -> Result<(), GonkGfxError> {
+        if frame.left >= frame.right || frame.top >= frame.bottom {
+            error!(target: LOG_TARGET, "Invalid display frame");
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        self.display_frame = Some(frame);
+        Ok(())
+    }
+
+    /// Goes back to showing the whole buffer at its own size.
+    pub fn reset_geometry(&mut self) {
+        self.source_crop = None;
+        self.display_frame = None;
+    }
+
+    /// Sets how buffers are fitted into the window when no source crop or
+    /// display frame is set. Defaults to `NoScaleCrop`.
+    pub fn set_scaling_mode(&mut self, mode: ScalingMode) {
+        self.scaling_mode = mode;
+    }
+
+    /// Switches to a single buffer for content that rarely changes, e.g. a
+    /// lock screen or an ambient display in Doze mode. The producer draws
+    /// into the buffer on screen, and queueing it again only reaches the
+    /// HWC when `set_surface_damage` reported a change. Leaving static mode
+    /// goes back to the previous buffer count.
+    pub fn set_static_content(&mut self, enabled: bool) -> Result<(), GonkGfxError> {
+        if enabled == self.static_content.is_some() {
+            return Ok(());
+        }
+        if enabled {
+            self.static_content = Some(self.bufs.len());
+            self.resize_buffer_set(1)
+        } else {
+            let count = self.static_content.take().unwrap();
+            self.resize_buffer_set(count)
+        }
+    }
+
+    fn resize_buffer_set(&mut self, count: usize) -> Result<(), GonkGfxError> {
+        if count == self.bufs.len() {
+            return Ok(());
+        }
+        self.release_buffers();
+        self.bufs = vec![None; count];
+        self.freed = vec![false; count];
+        self.fences = (0..count).map(|_| Fence::none()).collect();
+        self.last_idx = -1;
+        self.realloc_buffers()
+    }
+
+    /// Sets the parts of the buffer that changed since the last frame. This
+    /// only applies to the next queued buffer; an empty list means the whole
+    /// buffer is damaged, except in static mode where it means nothing
+    /// changed. The rects are clipped to the buffer before reaching the HWC,
+    /// which lets panels with partial updates refresh only those parts.
+    pub fn set_surface_damage(&mut self, rects: &[hwc_rect]) {
+        let bounds = hwc_rect::from_size(self.width, self.height);
+        self.damage = rects
+            .iter()
+            .filter_map(|rect| rect.intersection(&bounds))
+            .collect();
+    }
+
+    /// Changes the default size of the window, e.g. after the display it is
+    /// shown on switched modes. The window is resized to it and shows the
+    /// whole buffer again.
+    pub fn set_default_dimensions(&mut self, width: i32, height: i32) -> Result<(), GonkGfxError> {
+        if width <= 0 || height <= 0 {
+            error!(target: LOG_TARGET, "Invalid default dimensions {}x{}", width, height);
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        self.default_width = width;
+        self.default_height = height;
+        self.reset_geometry();
+        if width == self.width && height == self.height {
+            return Ok(());
+        }
+        self.width = width;
+        self.height = height;
+        self.realloc_buffers()
+    }
+
+    /// Changes the format of the buffers, reallocating them if needed.
+    pub fn set_pixel_format(&mut self, format: PixelFormat) -> Result<(), GonkGfxError> {
+        if self.format == format {
+            return Ok(());
+        }
+        self.format = format;
+        self.realloc_buffers()
+    }
+
+    /// Connects a producer using `api`, one of the NATIVE_WINDOW_API_*
+    /// values, as EGL does when a surface is created for the window. Buffers
+    /// can only be dequeued and queued while a producer is connected, and
+    /// only one can be at a time.
+    ///
+    /// This is where the buffers get allocated, unless `alloc_buffers` or
+    /// `alloc_buffers_async` already did: the size, format and usage have to
+    /// be set by then. Changing them afterwards reallocates the buffers
+    /// right away, while before connecting it only drops the current ones.
+    pub fn connect(&mut self, api: c_int) -> Result<(), GonkGfxError> {
+        if api < NATIVE_WINDOW_API_EGL || api > NATIVE_WINDOW_API_CAMERA {
+            error!(target: LOG_TARGET, "Unknown producer API {}", api);
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        if let Some(connected) = self.connected_api {
+            if connected != api {
+                error!(
+                    target: LOG_TARGET,
+                    "Producer API {} already connected, rejecting {}", connected, api
+                );
+                return Err(GonkGfxError::InvalidArgument);
+            }
+            return Ok(());
+        }
+        if self.width <= 0 || self.height <= 0 || self.usage | self.consumer_usage == 0 {
+            error!(
+                target: LOG_TARGET,
+                "Connecting before the window has a size and usage: {}x{}, usage {:#x}",
+                self.width,
+                self.height,
+                self.usage
+            );
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        self.finish_alloc()?;
+        if !self.allocated {
+            self.alloc_buffers()?;
+        }
+        self.connected_api = Some(api);
+        Ok(())
+    }
+
+    /// Disconnects the producer connected with `api`, letting another one
+    /// connect.
+    pub fn disconnect(&mut self, api: c_int) -> Result<(), GonkGfxError> {
+        if self.connected_api != Some(api) {
+            error!(target: LOG_TARGET, "Producer API {} isn't connected", api);
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        self.connected_api = None;
+        Ok(())
+    }
+
+    /// Dequeues a buffer the way a producer would, with the fence to wait on
+    /// before rendering into it. `NoBufferAvailable` means the producer
+    /// already holds all of them, and `NotConnected` that it has to
+    /// `connect` first.
+    pub fn dequeue(&mut self) -> Result<(*mut ANativeWindowBuffer, Fence), GonkGfxError> {
+        let mut buf = ptr::null_mut();
+        let mut fence = -1;
+        dequeue_or_wait(&mut self.window, &mut buf, &mut fence)?;
+        Ok((buf, Fence::from_raw(fence)))
+    }
+
+    /// Queues a rendered buffer for display, `fence` signaling the end of the
+    /// rendering.
+    pub fn queue(
+        &mut self,
+        buf: *mut ANativeWindowBuffer,
+        fence: Fence,
+    ) -> Result<(), GonkGfxError> {
+        let ret = queue_buffer(&mut self.window, buf, fence.into_raw());
+        if ret != 0 {
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(())
+    }
+
+    /// Hands a dequeued buffer back without displaying it.
+    pub fn cancel(
+        &mut self,
+        buf: *mut ANativeWindowBuffer,
+        fence: Fence,
+    ) -> Result<(), GonkGfxError> {
+        let ret = cancel_buffer(&mut self.window, buf, fence.into_raw());
+        if ret != 0 {
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(())
+    }
+
+    /// Presents the last queued buffer again, e.g. when the overlay or the
+    /// background changed while the window's content didn't.
+    pub fn force_redraw(&mut self) -> Result<(), GonkGfxError> {
+        if self.last_idx < 0 {
+            return Ok(());
+        }
+        let idx = self.last_idx as usize;
+        let buf = match self.bufs[idx] {
+            Some(buf) => buf,
+            // In static mode, the producer may be drawing into it.
+            None => return Err(GonkGfxError::NoBufferAvailable),
+        };
+        // The HWC may have dropped its composition plan, e.g. while blanked.
+        self.contents.geometry = None;
+        let fence = self.draw(idx, buf as *mut ANativeWindowBuffer, Fence::none())?;
+        // The buffer is free once both the previous and this frame are done
+        // with it.
+        let previous = replace(&mut self.fences[idx], Fence::none());
+        self.fences[idx] = match (previous.is_none(), fence.is_none()) {
+            // If merging fails, this frame's fence is the one signaling last
+            // anyway.
+            (false, false) => previous.merge(&fence).unwrap_or(fence),
+            (false, true) => previous,
+            _ => fence,
+        };
+        Ok(())
+    }
+
+    /// Sets how many vsyncs to wait for before presenting each frame, as
+    /// eglSwapInterval() does: 0 presents right away, 1 on the next vsync.
+    /// Waiting needs the HWC's vsync events, see `set_vsync_events`; without
+    /// them frames are presented right away whatever the interval.
+    pub fn set_swap_interval(&mut self, interval: c_int) {
+        self.swap_interval = interval.max(0).min(MAX_SWAP_INTERVAL);
+    }
+
+    /// Reports every frame sent to the HWC to a `VsyncScheduler`, which then
+    /// turns vsync off while the screen is static, see `VsyncScheduler`.
+    pub fn set_vsync_waker(&mut self, waker: Option<VsyncWaker>) {
+        self.vsync_waker = waker;
+    }
+
+    /// Makes dequeue_buffer wait up to `timeout` for a buffer to be queued
+    /// or cancelled when none is free, failing with `TimedOut` after that.
+    /// With None, the default, it fails right away with `NoBufferAvailable`.
+    pub fn set_dequeue_timeout(&mut self, timeout: Option<Duration>) {
+        self.dequeue_timeout = timeout;
+    }
+
+    /// Hands the window the receiver from `HwcDevice::events`, for the swap
+    /// interval to wait on, with vsync enabled on the primary display. The
+    /// HWC sends its events to a single receiver, so this can't be combined
+    /// with a `VsyncScheduler`.
+    pub fn set_vsync_events(&mut self, events: Option<Receiver<HwcEvent>>) {
+        self.vsync_events = events;
+    }
+
+    /// Reads back the buffer last queued for display, e.g. for screenshots.
+    /// Fails with `NoBufferAvailable` if there is none, or if the producer
+    /// dequeued it again in static mode. YV12 buffers are copied whole,
+    /// with their padding, and formats gralloc lays out as it likes are
+    /// `Unsupported`.
+    pub fn capture(&self) -> Result<Frame, GonkGfxError> {
+        if self.last_idx < 0 {
+            return Err(GonkGfxError::NoBufferAvailable);
+        }
+        let buf = match self.bufs[self.last_idx as usize] {
+            Some(buf) => buf,
+            None => return Err(GonkGfxError::NoBufferAvailable),
+        };
+        let buffer = unsafe { &(*buf).buffer };
+        let format = PixelFormat::from_gralloc(buffer.format);
+        let size = buffer_byte_size(buffer.width, buffer.height, buffer.stride, format)
+            .ok_or(GonkGfxError::Unsupported)?;
+        let locked = GrallocModule::new()?.lock(
+            buffer.handle,
+            GRALLOC_USAGE_SW_READ_OFTEN,
+            buffer.width,
+            buffer.height,
+        )?;
+        let pixels = unsafe { slice::from_raw_parts(locked.addr() as *const u8, size) };
+        let (stride, data) = match format.bytes_per_pixel() {
+            Some(bytes) => {
+                let row = buffer.width as usize * bytes;
+                let src_stride = buffer.stride as usize * bytes;
+                let mut data = Vec::with_capacity(row * buffer.height as usize);
+                for src in pixels.chunks(src_stride).take(buffer.height as usize) {
+                    data.extend_from_slice(&src[..row]);
+                }
+                (buffer.width, data)
+            }
+            None => (buffer.stride, pixels.to_vec()),
+        };
+        Ok(Frame {
+            width: buffer.width,
+            height: buffer.height,
+            stride,
+            format,
+            channel_order: format.channel_order(),
+            data,
+        })
+    }
+
+    /// Returns the timings of the HWC calls made so far.
+    pub fn stats(&self) -> FrameStats {
+        // The producer's threads update them under the buffer lock.
+        let _guard = self.buffer_wait.lock.lock().unwrap();
+        self.stats
+    }
+
+    /// Logs a summary of the frames drawn and buffers dequeued once a
+    /// second, rather than having to trace every frame to follow them.
+    pub fn set_log_summary(&mut self, enabled: bool) {
+        self.log_summary = if enabled {
+            Some((Instant::now(), self.stats()))
+        } else {
+            None
+        };
+    }
+
+    /// Warns when prepare() and set() take longer than `budget` together,
+    /// e.g. the `vsync_period_ns` of the display's `DisplayAttributes`, as
+    /// the frame then misses its vsync. At most one warning a second is
+    /// logged, counting the frames over budget since the previous one.
+    pub fn set_frame_budget(&mut self, budget: Option<Duration>) {
+        self.frame_budget = budget.map(|budget| FrameBudget {
+            budget,
+            last_warning: None,
+            frames_over: 0,
         });
+    }
+
+    // Logs the summary once its period is over, see set_log_summary().
+    fn flush_log_summary(&mut self) {
+        let (start, previous) = match self.log_summary {
+            Some(summary) => summary,
+            None => return,
+        };
+        let now = Instant::now();
+        if now - start < Duration::from_millis(LOG_SUMMARY_PERIOD_MS) {
+            return;
+        }
+        let stats = self.stats();
+        info!(
+            target: LOG_TARGET,
+            "{} frames drawn, {} skipped and {} buffers dequeued in {:?}, set() averaging {}us",
+            stats.frames - previous.frames,
+            stats.skipped_frames - previous.skipped_frames,
+            stats.dequeued - previous.dequeued,
+            now - start,
+            stats.avg_set_ns / 1000
+        );
+        self.log_summary = Some((now, stats));
+    }
 
-        unsafe { transmute(window) }
+    /// Sends a `PresentInfo` to `sender` for every frame once it is on
+    /// screen, or stops doing so with `None`. The retire fences are waited on
+    /// from a helper thread, so queueing doesn't block on them.
+    pub fn set_present_sender(&mut self, sender: Option<Sender<PresentInfo>>) {
+        self.present_sender = sender.map(spawn_present_thread);
     }
 
-    fn draw(&mut self, buf: *mut ANativeWindowBuffer, fence: c_int) -> c_int {
+    /// Shows `handle`, e.g. a YUV video frame, in its own layer above the
+    /// window's buffers, from the next queued frame on. Whether the HWC
+    /// scans it out directly or composites it with GL is up to its
+    /// prepare(), see `overlay_accepted`.
+    pub fn set_overlay(
+        &mut self,
+        handle: *const native_handle,
+        src_crop: hwc_frect,
+        dst_rect: hwc_rect,
+    ) -> Result<(), GonkGfxError> {
+        if handle.is_null()
+            || src_crop.left < 0.0
+            || src_crop.top < 0.0
+            || src_crop.left >= src_crop.right
+            || src_crop.top >= src_crop.bottom
+            || dst_rect.left >= dst_rect.right
+            || dst_rect.top >= dst_rect.bottom
+        {
+            error!(target: LOG_TARGET, "Invalid overlay");
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        self.overlay = Some(Overlay {
+            handle: handle,
+            source_crop: src_crop,
+            display_frame: dst_rect,
+            accepted: false,
+            release_fence: Fence::none(),
+        });
+        Ok(())
+    }
+
+    /// Sets how the overlay is blended over the window, e.g. to fade it out
+    /// with a decreasing `plane_alpha`, applied on top of the buffer's own
+    /// alpha. The HWC does the blending when it takes the overlay. Defaults
+    /// to opaque; the framebuffer target always is.
+    pub fn set_overlay_blending(&mut self, plane_alpha: u8, blending: Blending) {
+        self.overlay_plane_alpha = plane_alpha;
+        self.overlay_blending = blending;
+    }
+
+    /// Stops showing the overlay from the next queued frame on.
+    pub fn clear_overlay(&mut self) {
+        self.overlay = None;
+    }
+
+    /// Whether the HWC took the overlay as an HWC_OVERLAY layer for the last
+    /// frame. If not, it was composited into the framebuffer target, and
+    /// the player may want to render it with GL itself.
+    pub fn overlay_accepted(&self) -> bool {
+        self.overlay.as_ref().map_or(false, |overlay| overlay.accepted)
+    }
+
+    /// Has the HWC fill the screen behind the window with `color`, or not
+    /// with `None`, from the next queued frame on. Not every HWC supports
+    /// it, see `background_accepted`.
+    pub fn set_background_color(&mut self, color: Option<hwc_color>) {
+        self.background_color = color;
+        self.background_accepted = false;
+    }
+
+    /// Whether the HWC filled the background for the last frame. If not, the
+    /// producer has to clear what the window doesn't cover itself.
+    pub fn background_accepted(&self) -> bool {
+        self.background_accepted
+    }
+
+    /// Returns how the HWC composed the last frame, e.g. for a video player
+    /// to pause its GL rendering while the HWC scans out everything else.
+    pub fn composition(&self) -> CompositionResult {
+        self.composition
+    }
+
+    /// Starts or stops keeping a copy of the layers each frame is sent to
+    /// the HWC with, for `last_layers`. Off by default, as it costs a copy
+    /// per frame.
+    pub fn set_layer_capture(&mut self, enabled: bool) {
+        if enabled != self.last_layers.is_some() {
+            self.last_layers = if enabled { Some(Vec::with_capacity(4)) } else { None };
+        }
+    }
+
+    /// The layers the last frame was sent to the HWC with, bottom to top,
+    /// as set() left them. Empty unless `set_layer_capture` is on.
+    pub fn last_layers(&self) -> &[LayerInfo] {
+        match self.last_layers {
+            Some(ref layers) => layers,
+            None => &[],
+        }
+    }
+
+    /// Takes the release fence the HWC returned for the overlay in the last
+    /// frame, if any.
+    pub fn take_overlay_release_fence(&mut self) -> Fence {
+        match self.overlay {
+            Some(ref mut overlay) => replace(&mut overlay.release_fence, Fence::none()),
+            None => Fence::none(),
+        }
+    }
+
+    /// Waits for the fences still pending on the window's buffers and the
+    /// overlay, and closes them. A hung HWC doesn't block this for more than
+    /// FENCE_DRAIN_TIMEOUT_MS: the remaining fences are closed anyway.
+    pub fn drain(&mut self) {
+        let deadline = Instant::now() + Duration::from_millis(FENCE_DRAIN_TIMEOUT_MS);
+        let overlay_fence = self.take_overlay_release_fence();
+        let fences = self
+            .fences
+            .iter_mut()
+            .chain(Some(&mut self.last_fence))
+            .map(|fence| replace(fence, Fence::none()))
+            .chain(Some(overlay_fence))
+            .filter(|fence| !fence.is_none());
+        for fence in fences {
+            let fd = fence.as_raw();
+            if !wait_fence_until(fence, Some(deadline)) {
+                error!(target: LOG_TARGET, "Gave up waiting on fence {}", fd);
+            }
+        }
+    }
+
+    fn hwc_source_crop(&self, crop: hwc_frect) -> hwc_source_crop {
+        if self.float_crop {
+            hwc_source_crop {
+                source_crop_f: crop,
+            }
+        } else {
+            hwc_source_crop {
+                source_crop_i: crop.round_out(),
+            }
+        }
+    }
+
+    // With a swap interval of N, waits for the Nth next vsync before handing
+    // the frame to the HWC. Vsyncs that happened before queueing don't count.
+    fn wait_for_vsync(&mut self) {
+        if self.swap_interval == 0 {
+            return;
+        }
+        let mut seen = 0;
+        let mut disconnected = false;
+        if let Some(ref events) = self.vsync_events {
+            for _ in events.try_iter() {}
+            while seen < self.swap_interval {
+                match events.recv_timeout(Duration::from_millis(VSYNC_TIMEOUT_MS)) {
+                    Ok(HwcEvent::Vsync { display, .. }) if display == HWC_DISPLAY_PRIMARY => {
+                        seen += 1
+                    }
+                    Ok(_) => (),
+                    Err(RecvTimeoutError::Timeout) => {
+                        warn!(target: LOG_TARGET, "No vsync, presenting anyway");
+                        break;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        disconnected = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if disconnected {
+            // The HWC device is gone, or moved its events elsewhere.
+            self.vsync_events = None;
+        }
+    }
+
+    // Returns the fence to wait on before drawing into `buf` again. A set()
+    // that fails transiently is tried once more; if that fails too, or set()
+    // fails otherwise, the frame isn't shown and the error is returned.
+    fn draw(
+        &mut self,
+        idx: usize,
+        buf: *mut ANativeWindowBuffer,
+        fence: Fence,
+    ) -> Result<Fence, GonkGfxError> {
         let gonkbuf: &mut GonkNativeWindowBuffer = unsafe { transmute(buf) };
-        info!("draw {}x{}", gonkbuf.buffer.width, gonkbuf.buffer.height);
-        let rect = hwc_rect {
-            left: 0,
-            top: 0,
-            right: gonkbuf.buffer.width,
-            bottom: gonkbuf.buffer.height,
+        trace!(
+            target: LOG_TARGET,
+            "draw {}x{}",
+            gonkbuf.buffer.width,
+            gonkbuf.buffer.height
+        );
+        self.flush_log_summary();
+        let (default_crop, default_rect) = match self.scaling_mode.geometry(
+            gonkbuf.buffer.width,
+            gonkbuf.buffer.height,
+            self.default_width,
+            self.default_height,
+        ) {
+            Some(geometry) => geometry,
+            None => {
+                // Frozen: keep showing the last frame, and hand the buffer
+                // back once rendering into it is done.
+                trace!(target: LOG_TARGET, "Not showing a buffer of the wrong size");
+                self.damage.clear();
+                return Ok(fence);
+            }
         };
-        let mut list = hwc_display_contents {
-            retire_fence_fd: -1,
-            outbuf: ptr::null(),
-            outbuf_acquire_fence_fd: -1,
-            flags: HWC_GEOMETRY_CHANGED,
-            num_hw_layers: 2,
-            hw_layers: [
-                hwc_layer {
-                    composition_type: HWC_FRAMEBUFFER,
-                    hints: 0,
-                    flags: HWC_SKIP_LAYER,
-                    handle: ptr::null(),
-                    transform: 0,
-                    blending: 0,
-                    source_crop: hwc_frect {
-                        left: 0.0,
-                        top: 0.0,
-                        right: 0.0,
-                        bottom: 0.0,
-                    },
-                    display_frame: rect,
-                    visible_region_screen: hwc_region {
-                        num_rects: 0,
-                        rects: ptr::null(),
-                    },
-                    acquire_fence_fd: -1,
-                    release_fence_fd: -1,
-                    plane_alpha: 0xff,
-                    pad: [0; 3],
-                    surface_damage: hwc_region {
-                        num_rects: 0,
-                        rects: ptr::null(),
-                    },
-                    reserved: [0; 12],
-                },
-                hwc_layer {
-                    composition_type: HWC_FRAMEBUFFER_TARGET,
-                    hints: 0,
-                    flags: 0,
-                    handle: gonkbuf.buffer.handle,
-                    transform: 0,
-                    blending: HWC_BLENDING_NONE,
-                    source_crop: hwc_frect {
-                        left: 0.0,
-                        top: 0.0,
-                        right: gonkbuf.buffer.width as f32,
-                        bottom: gonkbuf.buffer.height as f32,
-                    },
-                    display_frame: rect,
-                    visible_region_screen: hwc_region {
-                        num_rects: 1,
-                        rects: &rect,
-                    },
-                    acquire_fence_fd: fence,
-                    release_fence_fd: -1,
-                    plane_alpha: 0xff,
-                    pad: [0; 3],
-                    surface_damage: hwc_region {
-                        num_rects: 0,
-                        rects: ptr::null(),
-                    },
-                    reserved: [0; 12],
+        self.wait_for_vsync();
+        let rect = self.display_frame.unwrap_or(default_rect);
+        let crop = self.source_crop.unwrap_or(default_crop);
+        let source_crop = self.hwc_source_crop(crop);
+        let overlay_crop = self
+            .overlay
+            .as_ref()
+            .map(|overlay| self.hwc_source_crop(overlay.source_crop));
+        let geometry = FrameGeometry {
+            display_frame: rect,
+            source_crop: crop,
+            transform: self.transform,
+            background: self.background_color.is_some(),
+            overlay: self.overlay.as_ref().map(|overlay| {
+                (
+                    overlay.source_crop,
+                    overlay.display_frame,
+                    self.overlay_plane_alpha,
+                    self.overlay_blending,
+                )
+            }),
+        };
+        // Held to update the stats, see stats().
+        let wait = self.buffer_wait.clone();
+        let contents = &mut *self.contents;
+        let geometry_changed = contents.geometry != Some(geometry);
+        contents.geometry = Some(geometry);
+        contents.target_visible = rect;
+
+        // Bottom to top, the background first and the target last. Every
+        // layer is filled in from scratch, as prepare() and set() write
+        // their decisions and fences into them.
+        let mut num_layers = 0;
+        let background_idx = self.background_color.map(|color| {
+            // Only the color, which shares the handle's spot, matters for it.
+            let layer = reset_layer(&mut contents.list.hw_layers[num_layers], HWC_BACKGROUND);
+            layer.handle = color.as_handle();
+            num_layers += 1;
+            num_layers - 1
+        });
+        let skip_idx = if self.skip_layer {
+            let layer = reset_layer(&mut contents.list.hw_layers[num_layers], HWC_FRAMEBUFFER);
+            layer.flags = HWC_SKIP_LAYER;
+            layer.display_frame = rect;
+            num_layers += 1;
+            Some(num_layers - 1)
+        } else {
+            None
+        };
+        // Between the window's layer, which stands for everything composited
+        // with GL, and the framebuffer target which has to come last.
+        let overlay_idx = match (self.overlay.as_ref(), overlay_crop) {
+            (Some(overlay), Some(overlay_crop)) => {
+                contents.overlay_visible = overlay.display_frame;
+                let layer =
+                    reset_layer(&mut contents.list.hw_layers[num_layers], HWC_FRAMEBUFFER);
+                layer.handle = overlay.handle;
+                layer.blending = self.overlay_blending.as_hwc();
+                layer.source_crop = overlay_crop;
+                layer.display_frame = overlay.display_frame;
+                layer.visible_region_screen = hwc_region {
+                    num_rects: 1,
+                    rects: &contents.overlay_visible,
+                };
+                layer.plane_alpha = self.overlay_plane_alpha;
+                num_layers += 1;
+                Some(num_layers - 1)
+            }
+            _ => None,
+        };
+        let retry_fence;
+        {
+            let layer = reset_layer(
+                &mut contents.list.hw_layers[num_layers],
+                HWC_FRAMEBUFFER_TARGET,
+            );
+            layer.handle = gonkbuf.buffer.handle;
+            layer.transform = self.transform as u32;
+            layer.blending = HWC_BLENDING_NONE;
+            layer.source_crop = source_crop;
+            layer.display_frame = rect;
+            layer.visible_region_screen = hwc_region {
+                num_rects: 1,
+                rects: &contents.target_visible,
+            };
+            // Kept for a retry, as set() closes the one in the layer even
+            // when failing.
+            retry_fence = fence.try_clone();
+            layer.acquire_fence_fd = fence.into_raw();
+            // Cleared once set() is done with it.
+            layer.surface_damage = hwc_region {
+                num_rects: self.damage.len() as i32,
+                rects: if self.damage.is_empty() {
+                    ptr::null()
+                } else {
+                    self.damage.as_ptr()
                 },
-            ],
+            };
+            num_layers += 1;
+        }
+        let list = &mut contents.list;
+        list.retire_fence_fd = -1;
+        list.outbuf = ptr::null();
+        list.outbuf_acquire_fence_fd = -1;
+        // Lets the HWC reuse its composition plan while only the buffers
+        // change.
+        list.flags = if geometry_changed {
+            HWC_GEOMETRY_CHANGED
+        } else {
+            0
         };
+        list.num_hw_layers = num_layers;
         unsafe {
             let mut displays: [*mut hwc_display_contents; HWC_NUM_DISPLAY_TYPES] =
-                [&mut list, ptr::null_mut(), ptr::null_mut()];
+                [&mut *list, ptr::null_mut(), ptr::null_mut()];
+            let prepare_start = Instant::now();
             let prep_res = ((*self.hwc_dev).prepare)(
                 self.hwc_dev,
                 displays.len() as size_t,
                 transmute(displays.as_mut_ptr()),
             );
-            info!("hwc.prepare returned {}", prep_res);
-            let set_res = ((*self.hwc_dev).set)(
+            let set_start = Instant::now();
+            trace!(target: LOG_TARGET, "hwc.prepare returned {}", prep_res);
+            self.composition = CompositionResult::from_layers(
+                list.hw_layers[..num_layers - 1]
+                    .iter()
+                    .enumerate()
+                    .filter(|&(idx, _)| Some(idx) != skip_idx)
+                    .map(|(_, layer)| layer),
+            );
+            // An HWC that can't fill the background turns it into a
+            // framebuffer layer, which is up to GL to clear.
+            self.background_accepted = background_idx.map_or(false, |idx| {
+                list.hw_layers[idx].composition_type == HWC_BACKGROUND
+            });
+            let mut set_res = ((*self.hwc_dev).set)(
                 self.hwc_dev,
                 displays.len() as size_t,
                 transmute(displays.as_mut_ptr()),
             );
-            info!("hwc.set returned {}", set_res);
-            if list.retire_fence_fd >= 0 {
-                close(list.retire_fence_fd);
+            match retry_fence {
+                Some(fence) if set_res == -EBUSY || set_res == -EAGAIN => {
+                    warn!(target: LOG_TARGET, "hwc.set failed with {}, retrying", set_res);
+                    thread::sleep(Duration::from_millis(SET_RETRY_DELAY_MS));
+                    close_set_fences(list, num_layers);
+                    list.hw_layers[num_layers - 1].acquire_fence_fd = fence.into_raw();
+                    set_res = ((*self.hwc_dev).set)(
+                        self.hwc_dev,
+                        displays.len() as size_t,
+                        transmute(displays.as_mut_ptr()),
+                    );
+                }
+                _ => (),
+            }
+            let set_end = Instant::now();
+            trace!(target: LOG_TARGET, "hwc.set returned {}", set_res);
+            {
+                let _guard = wait.lock.lock().unwrap();
+                self.stats
+                    .record(set_start - prepare_start, set_end - set_start);
+            }
+            if let Some(ref mut budget) = self.frame_budget {
+                budget.check(set_end - prepare_start);
+            }
+            if set_res != 0 {
+                error!(target: LOG_TARGET, "hwc.set failed with {}, dropping the frame", set_res);
+                close_set_fences(list, num_layers);
+                // The HWC may not have kept the plan prepare() made.
+                contents.geometry = None;
+                self.damage.clear();
+                return Err(GonkGfxError::from_status(set_res));
+            }
+            if let Some(ref mut captured) = self.last_layers {
+                let float_crop = self.float_crop;
+                captured.clear();
+                captured.extend(
+                    list.hw_layers[..num_layers]
+                        .iter()
+                        .map(|layer| LayerInfo::from_layer(layer, float_crop)),
+                );
+            }
+            let retire_fence = Fence::from_raw(list.retire_fence_fd);
+            let sent = match self.present_sender {
+                Some(ref sender) => sender.send((idx, retire_fence, set_end)).is_ok(),
+                None => false,
+            };
+            if !sent && self.present_sender.is_some() {
+                // The present thread quit, its receiver is gone.
+                self.present_sender = None;
+            }
+        }
+        self.damage.clear();
+        if let Some(idx) = overlay_idx {
+            let layer = &list.hw_layers[idx];
+            if let Some(ref mut overlay) = self.overlay {
+                overlay.accepted = layer.composition_type == HWC_OVERLAY;
+                overlay.release_fence = Fence::from_raw(layer.release_fence_fd);
+            }
+        }
+        let target = &list.hw_layers[num_layers - 1];
+        Ok(Fence::from_raw(target.release_fence_fd))
+    }
+
+    // Applies a change of size, format or usage to the buffers: reallocates
+    // them while a producer is connected, or drops them for connect() to
+    // allocate.
+    fn realloc_buffers(&mut self) -> Result<(), GonkGfxError> {
+        if self.connected_api.is_some() {
+            self.alloc_buffers()
+        } else {
+            self.release_buffers();
+            Ok(())
+        }
+    }
+
+    // The slots holding a buffer, dequeued or not.
+    fn slot_count(&self) -> usize {
+        self.freed.iter().filter(|&&freed| !freed).count()
+    }
+
+    /// Frees the buffer in slot `idx`, e.g. to go down to single buffering
+    /// while memory is tight, until `realloc_slot`. Neither the producer nor
+    /// the HWC may be using it: it fails with `NoBufferAvailable` for the
+    /// buffer on screen, a dequeued one, or one whose release fence didn't
+    /// signal yet. One buffer at least has to remain.
+    pub fn free_slot(&mut self, idx: usize) -> Result<(), GonkGfxError> {
+        if idx >= self.bufs.len() || self.freed[idx] || self.slot_count() < 2 {
+            error!(target: LOG_TARGET, "Can't free buffer slot {}", idx);
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        let buf = self.idle_buffer(idx)?;
+        self.bufs[idx] = None;
+        self.fences[idx] = Fence::none();
+        self.freed[idx] = true;
+        unsafe {
+            ((*buf).buffer.common.dec_ref)(&mut (*buf).buffer.common);
+        }
+        Ok(())
+    }
+
+    // The buffer in slot `idx`, if neither the producer nor the HWC is
+    // using it.
+    fn idle_buffer(&self, idx: usize) -> Result<*mut GonkNativeWindowBuffer, GonkGfxError> {
+        match self.bufs[idx] {
+            Some(buf) if idx as i32 != self.last_idx && self.fences[idx].is_signaled() => Ok(buf),
+            _ => {
+                debug!(target: LOG_TARGET, "Buffer slot {} is still in use", idx);
+                Err(GonkGfxError::NoBufferAvailable)
+            }
+        }
+    }
+
+    /// Allocates a buffer again in a slot `free_slot` emptied, like the
+    /// other buffers of the window.
+    pub fn realloc_slot(&mut self, idx: usize) -> Result<(), GonkGfxError> {
+        if idx >= self.bufs.len() || !self.freed[idx] {
+            error!(target: LOG_TARGET, "Buffer slot {} wasn't freed", idx);
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        self.bufs[idx] = Some(self.alloc_one()?);
+        self.freed[idx] = false;
+        Ok(())
+    }
+
+    /// Takes the buffer in slot `idx` out of the window, e.g. to hand the
+    /// frame just queued to a video encoder, and allocates another one in
+    /// its place so the buffer count stays the same. The caller gets the
+    /// window's reference on it, to `dec_ref` or give back with
+    /// `attach_buffer`, and the fence signaling once the HWC is done with
+    /// it; the buffer on screen mustn't be released before that. A buffer
+    /// the producer dequeued can't be detached.
+    pub fn detach_buffer(
+        &mut self,
+        idx: usize,
+    ) -> Result<(*mut GonkNativeWindowBuffer, Fence), GonkGfxError> {
+        if idx >= self.bufs.len() || self.freed[idx] {
+            error!(target: LOG_TARGET, "No buffer to detach in slot {}", idx);
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        let buf = match self.bufs[idx] {
+            Some(buf) => buf,
+            None => {
+                debug!(target: LOG_TARGET, "Buffer slot {} is dequeued", idx);
+                return Err(GonkGfxError::NoBufferAvailable);
+            }
+        };
+        self.bufs[idx] = Some(self.alloc_one()?);
+        if idx as i32 == self.last_idx {
+            // Nothing to redraw or capture until the next frame.
+            self.last_idx = -1;
+            self.static_shown = false;
+        }
+        Ok((buf, replace(&mut self.fences[idx], Fence::none())))
+    }
+
+    /// Puts `buf`, e.g. from `detach_buffer`, in slot `idx`, taking over the
+    /// caller's reference on it. It must have the window's size and format,
+    /// and `fence` signals once it can be rendered into. The buffer it
+    /// replaces is released, and must be as unused as for `free_slot`.
+    pub fn attach_buffer(
+        &mut self,
+        idx: usize,
+        buf: *mut GonkNativeWindowBuffer,
+        fence: Fence,
+    ) -> Result<(), GonkGfxError> {
+        if idx >= self.bufs.len() || buf.is_null() {
+            error!(target: LOG_TARGET, "Can't attach a buffer in slot {}", idx);
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        let (width, height, format) = unsafe {
+            let buffer = &(*buf).buffer;
+            (buffer.width, buffer.height, buffer.format)
+        };
+        if width != self.width
+            || height != self.height
+            || format != self.allocated_format.as_gralloc()
+        {
+            error!(
+                target: LOG_TARGET,
+                "Can't attach a {}x{} buffer of format {} to a {}x{} {:?} window",
+                width,
+                height,
+                format,
+                self.width,
+                self.height,
+                self.allocated_format
+            );
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        if !self.freed[idx] {
+            let old = self.idle_buffer(idx)?;
+            unsafe {
+                ((*old).buffer.common.dec_ref)(&mut (*old).buffer.common);
             }
         }
-        list.hw_layers[1].release_fence_fd
+        unsafe {
+            // Dequeueable and queueable from now on.
+            (*buf).generation = self.generation;
+        }
+        self.bufs[idx] = Some(buf);
+        self.fences[idx] = fence;
+        self.freed[idx] = false;
+        Ok(())
     }
 
-    pub fn alloc_buffers(&mut self) {
-        info!("alloc_buffers");
-        self.bufs[0] = Some(GonkNativeWindowBuffer::new(
-            self.alloc_dev,
-            self.width,
-            self.height,
-            self.format,
-            self.usage,
-        ));
-        self.bufs[1] = Some(GonkNativeWindowBuffer::new(
-            self.alloc_dev,
-            self.width,
-            self.height,
-            self.format,
-            self.usage,
-        ));
+    /// Puts a buffer allocated elsewhere, e.g. by a video decoder, in slot
+    /// `idx`, so that it can be dequeued and queued without a copy. See
+    /// `GonkNativeWindowBuffer::from_external` for the handle's ownership,
+    /// and `attach_buffer` for the size and format it must have.
+    pub fn attach_external_buffer(
+        &mut self,
+        idx: usize,
+        handle: *const native_handle,
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: PixelFormat,
+    ) -> Result<(), GonkGfxError> {
+        let buf = GonkNativeWindowBuffer::from_external(
+            handle,
+            width,
+            height,
+            stride,
+            format.as_gralloc(),
+            self.usage | self.consumer_usage,
+        )?;
+        self.attach_buffer(idx, buf, Fence::none()).map_err(|err| {
+            unsafe {
+                ((*buf).buffer.common.dec_ref)(&mut (*buf).buffer.common);
+            }
+            err
+        })
+    }
+
+    // Allocates a buffer like the ones of the current set.
+    fn alloc_one(&self) -> Result<*mut GonkNativeWindowBuffer, GonkGfxError> {
+        let spec = BufferSetSpec {
+            dev: self.alloc_dev.clone(),
+            width: self.width,
+            height: self.height,
+            format: self.allocated_format,
+            usage: self.usage | self.consumer_usage,
+            count: 1,
+            generation: self.generation,
+        };
+        Ok(spec.alloc_in(self.allocated_format)?[0])
+    }
+
+    /// Allocates a fresh set of buffers, releasing the previous ones.
+    pub fn alloc_buffers(&mut self) -> Result<(), GonkGfxError> {
+        info!(target: LOG_TARGET, "alloc_buffers");
+        self.release_buffers();
+        let set = self.next_buffer_set().alloc()?;
+        self.install_buffers(set);
+        Ok(())
+    }
+
+    /// Like `alloc_buffers`, but allocates on a helper thread so the caller
+    /// can go on with its own initialization. The receiver gets the outcome
+    /// once the buffers are ready; dequeueing before that waits for them.
+    pub fn alloc_buffers_async(&mut self) -> Receiver<Result<(), GonkGfxError>> {
+        info!(target: LOG_TARGET, "alloc_buffers_async");
+        self.release_buffers();
+        let spec = self.next_buffer_set();
+        let (sender, receiver) = channel();
+        self.pending_alloc = Some(thread::spawn(move || {
+            let result = spec.alloc();
+            let _ = sender.send(result.as_ref().map(|_| ()).map_err(|&err| err));
+            result
+        }));
+        receiver
+    }
+
+    // Waits for an alloc_buffers_async() in flight and takes its buffers.
+    fn finish_alloc(&mut self) -> Result<(), GonkGfxError> {
+        let pending = match self.pending_alloc.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let set = pending.join().expect("Buffer allocation thread panicked")?;
+        self.install_buffers(set);
+        Ok(())
+    }
+
+    // Whether dequeueing has buffers to take from alloc_buffers_async(), or
+    // to allocate, first.
+    fn alloc_pending(&self) -> bool {
+        self.pending_alloc.is_some() || !self.allocated
+    }
+
+    fn next_buffer_set(&mut self) -> BufferSetSpec {
+        self.generation = self.generation.wrapping_add(1);
+        BufferSetSpec {
+            dev: self.alloc_dev.clone(),
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            usage: self.usage | self.consumer_usage,
+            count: self.bufs.len(),
+            generation: self.generation,
+        }
+    }
+
+    fn install_buffers(&mut self, set: BufferSet) {
+        for (slot, buf) in self.bufs.iter_mut().zip(set.bufs) {
+            *slot = Some(buf);
+        }
+        self.allocated_format = set.format;
+        self.allocated = true;
+    }
+
+    /// The format the buffers were actually allocated in, which differs from
+    /// the requested one when gralloc only supported a fallback, see
+    /// `PixelFormat::fallback`.
+    pub fn allocated_format(&self) -> PixelFormat {
+        self.allocated_format
+    }
+
+    // Drops our reference on every buffer once the HWC is done with them. A
+    // buffer the producer still holds is freed when it releases it.
+    fn release_buffers(&mut self) {
+        if let Err(err) = self.finish_alloc() {
+            warn!(target: LOG_TARGET, "Pending buffer allocation failed: {}", err);
+        }
+        self.drain();
+        self.allocated = false;
+        self.static_shown = false;
+        self.contents.geometry = None;
+        // The next set fills every slot.
+        for freed in self.freed.iter_mut() {
+            *freed = false;
+        }
+        for buf in self.bufs.iter_mut() {
+            if let Some(buf) = buf.take() {
+                unsafe {
+                    ((*buf).buffer.common.dec_ref)(&mut (*buf).buffer.common);
+                }
+            }
+        }
+    }
+}
+
+/// What the layers above the native window use of it, so that they can run
+/// against another implementation, e.g. a fake one in their tests.
+/// `GonkNativeWindow` implements it through the same paths as its C
+/// callbacks, on the stub backend as on devices.
+pub trait NativeWindow {
+    /// Connects a producer, see `GonkNativeWindow::connect`.
+    fn connect(&mut self, api: c_int) -> Result<(), GonkGfxError>;
+    fn disconnect(&mut self, api: c_int) -> Result<(), GonkGfxError>;
+    /// Returns a buffer to render into, with the fence to wait on first.
+    fn dequeue(&mut self) -> Result<(*mut ANativeWindowBuffer, Fence), GonkGfxError>;
+    /// Displays a buffer from `dequeue` once `fence` signals.
+    fn queue(&mut self, buf: *mut ANativeWindowBuffer, fence: Fence) -> Result<(), GonkGfxError>;
+    /// Returns a buffer from `dequeue` without displaying it.
+    fn cancel(&mut self, buf: *mut ANativeWindowBuffer, fence: Fence) -> Result<(), GonkGfxError>;
+    /// The size of the buffers, in pixels.
+    fn dimensions(&self) -> (i32, i32);
+    /// The pixel format of the buffers.
+    fn format(&self) -> PixelFormat;
+}
+
+impl NativeWindow for GonkNativeWindow {
+    fn connect(&mut self, api: c_int) -> Result<(), GonkGfxError> {
+        GonkNativeWindow::connect(self, api)
+    }
+
+    fn disconnect(&mut self, api: c_int) -> Result<(), GonkGfxError> {
+        GonkNativeWindow::disconnect(self, api)
+    }
+
+    fn dequeue(&mut self) -> Result<(*mut ANativeWindowBuffer, Fence), GonkGfxError> {
+        GonkNativeWindow::dequeue(self)
+    }
+
+    fn queue(&mut self, buf: *mut ANativeWindowBuffer, fence: Fence) -> Result<(), GonkGfxError> {
+        GonkNativeWindow::queue(self, buf, fence)
+    }
+
+    fn cancel(&mut self, buf: *mut ANativeWindowBuffer, fence: Fence) -> Result<(), GonkGfxError> {
+        GonkNativeWindow::cancel(self, buf, fence)
+    }
+
+    fn dimensions(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.allocated_format()
+    }
+}
+
+impl Drop for GonkNativeWindow {
+    fn drop(&mut self) {
+        self.release_buffers();
     }
 }
 
@@ -506,13 +2892,14 @@ extern "C" fn gnwb_dec_ref(base: *mut ANativeBase) {
 
 impl GonkNativeWindowBuffer {
     pub fn new(
-        dev: *mut alloc_device,
+        dev: &Arc<AllocDevice>,
         width: i32,
         height: i32,
         format: c_int,
         usage: c_int,
-    ) -> *mut GonkNativeWindowBuffer {
+    ) -> Result<*mut GonkNativeWindowBuffer, GonkGfxError> {
         info!(
+            target: LOG_TARGET,
             "GonkNativeWindowBuffer::new {}x{} {} {}",
             width, height, format, usage
         );
@@ -535,10 +2922,14 @@ impl GonkNativeWindowBuffer {
                 reserved_proc: unsafe { zeroed() },
             },
             count: 1,
+            alloc_dev: Some(dev.clone()),
+            generation: 0,
+            external: None,
         });
 
         let ret = unsafe {
-            info!("About to call alloc {:?}", (*dev).alloc);
+            let dev = dev.native();
+            debug!(target: LOG_TARGET, "About to call alloc {:?}", (*dev).alloc);
 
             ((*dev).alloc)(
                 dev,
@@ -550,8 +2941,136 @@ impl GonkNativeWindowBuffer {
                 &mut buf.buffer.stride,
             )
         };
-        assert!(ret == 0, "Failed to allocate gralloc buffer!");
+        if ret != 0 {
+            error!(target: LOG_TARGET, "Failed to allocate gralloc buffer: {}", ret);
+            return Err(GonkGfxError::from_status(ret));
+        }
+        // Some grallocs report success without a buffer. Dropping `buf` frees
+        // the handle if there is one.
+        if buf.buffer.handle.is_null() || buf.buffer.stride <= 0 {
+            error!(
+                target: LOG_TARGET,
+                "gralloc returned a bogus buffer (handle {:?}, stride {})",
+                buf.buffer.handle,
+                buf.buffer.stride
+            );
+            return Err(GonkGfxError::OutOfMemory);
+        }
+
+        Ok(unsafe { transmute(buf) })
+    }
+
+    /// Wraps a buffer allocated by someone else, e.g. a video decoder, after
+    /// registering its handle with gralloc. Dropping the last reference
+    /// unregisters the handle rather than freeing the buffer; the handle
+    /// itself stays the caller's, and must outlive the wrapper.
+    pub fn from_external(
+        handle: *const native_handle,
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: c_int,
+        usage: c_int,
+    ) -> Result<*mut GonkNativeWindowBuffer, GonkGfxError> {
+        info!(
+            target: LOG_TARGET,
+            "GonkNativeWindowBuffer::from_external {}x{} {} {}",
+            width, height, format, usage
+        );
+        if width <= 0 || height <= 0 || stride < width {
+            error!(
+                target: LOG_TARGET,
+                "Bogus external buffer size {}x{}, stride {}", width, height, stride
+            );
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        let module = GrallocModule::new()?;
+        module.register_buffer(handle)?;
+        let buf = Box::new(GonkNativeWindowBuffer {
+            buffer: ANativeWindowBuffer {
+                common: ANativeBase {
+                    magic: ANativeBase::magic('_', 'b', 'f', 'r'),
+                    version: size_of::<ANativeBase>() as u32,
+                    reserved: unsafe { zeroed() },
+                    inc_ref: gnwb_inc_ref,
+                    dec_ref: gnwb_dec_ref,
+                },
+                width: width,
+                height: height,
+                stride: stride,
+                format: format,
+                usage: usage,
+                reserved: unsafe { zeroed() },
+                handle: handle,
+                reserved_proc: unsafe { zeroed() },
+            },
+            count: 1,
+            alloc_dev: None,
+            generation: 0,
+            external: Some(module),
+        });
+        Ok(unsafe { transmute(buf) })
+    }
+}
+
+impl Drop for GonkNativeWindowBuffer {
+    fn drop(&mut self) {
+        if self.buffer.handle.is_null() {
+            return;
+        }
+        if let Some(module) = self.external {
+            debug!(target: LOG_TARGET, "Unregistering buffer {:?}", self.buffer.handle);
+            let _ = module.unregister_buffer(self.buffer.handle);
+            return;
+        }
+        if let Some(ref dev) = self.alloc_dev {
+            debug!(target: LOG_TARGET, "Freeing gralloc buffer {:?}", self.buffer.handle);
+            let dev = dev.native();
+            unsafe {
+                ((*dev).free)(dev, self.buffer.handle);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 2x1 frame of `format` holding `data`.
+    fn frame(format: PixelFormat, data: Vec<u8>) -> Frame {
+        Frame {
+            width: 2,
+            height: 1,
+            stride: 2,
+            format: format,
+            channel_order: format.channel_order(),
+            data: data,
+        }
+    }
+
+    #[test]
+    fn rgba_to_rgba8() {
+        let data = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(frame(PixelFormat::Rgba8888, data.clone()).to_rgba8(), data);
+    }
+
+    #[test]
+    fn rgbx_to_rgba8() {
+        let rgba = frame(PixelFormat::Rgbx8888, vec![1, 2, 3, 0, 5, 6, 7, 0x42]).to_rgba8();
+        assert_eq!(rgba, vec![1, 2, 3, 0xff, 5, 6, 7, 0xff]);
+    }
+
+    #[test]
+    fn bgra_to_rgba8() {
+        let rgba = frame(PixelFormat::Bgra8888, vec![1, 2, 3, 4, 5, 6, 7, 8]).to_rgba8();
+        assert_eq!(rgba, vec![3, 2, 1, 4, 7, 6, 5, 8]);
+    }
 
-        unsafe { transmute(buf) }
+    #[test]
+    fn rgb565_to_rgba8() {
+        // Red, then green, as little-endian words.
+        let rgba = frame(PixelFormat::Rgb565, vec![0x00, 0xf8, 0xe0, 0x07]).to_rgba8();
+        assert_eq!(rgba, vec![255, 0, 0, 255, 0, 255, 0, 255]);
     }
 }
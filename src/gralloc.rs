@@ -2,12 +2,18 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use gonk_gfx::native_handle;
+use errno::errno;
+use error::GonkGfxError;
+use gonk_gfx::{handle_fds, native_handle};
 use hardware::*;
-use libc::{c_char, c_int, c_void, size_t};
-use std::ffi::CString;
+use hwc::DUMP_BUFFER_SIZE;
+use libc::{c_char, c_int, c_void, lseek, size_t, SEEK_END};
+use std::ffi::{CStr, CString};
 use std::mem::transmute;
 use std::ptr;
+use std::time::Duration;
+
+const LOG_TARGET: &'static str = "gonk-gfx::gralloc";
 
 // From system/core/include/system/graphics.h
 
@@ -22,25 +28,155 @@ pub struct android_ycbcr {
     reserved: [u32; 8],
 }
 
+// From system/core/include/system/graphics.h
+
+pub const HAL_PIXEL_FORMAT_RGBA_8888: c_int = 1;
+pub const HAL_PIXEL_FORMAT_RGBX_8888: c_int = 2;
+pub const HAL_PIXEL_FORMAT_RGB_888: c_int = 3;
+pub const HAL_PIXEL_FORMAT_RGB_565: c_int = 4;
+pub const HAL_PIXEL_FORMAT_BGRA_8888: c_int = 5;
+pub const HAL_PIXEL_FORMAT_YV12: c_int = 0x32315659;
+
+/// How the channels of a packed pixel are laid out in memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelOrder {
+    /// Red, green, blue and alpha bytes.
+    Rgba,
+    /// Red, green and blue bytes, then one to ignore.
+    Rgbx,
+    /// Red, green and blue bytes.
+    Rgb,
+    /// Blue, green, red and alpha bytes.
+    Bgra,
+    /// A little-endian 16-bit word, with red in the top 5 bits, then 6 of
+    /// green and 5 of blue.
+    Rgb565,
+}
+
+/// The pixel format of gralloc buffers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    Rgba8888,
+    Rgbx8888,
+    Rgb888,
+    Rgb565,
+    Bgra8888,
+    Yv12,
+    /// Format 0, which leaves the choice to gralloc. Some EGL drivers ask
+    /// for it on purpose.
+    ImplementationDefined,
+    /// Any other HAL format, passed as is to gralloc.
+    Raw(c_int),
+}
+
+impl PixelFormat {
+    pub fn from_gralloc(format: c_int) -> PixelFormat {
+        match format {
+            HAL_PIXEL_FORMAT_RGBA_8888 => PixelFormat::Rgba8888,
+            HAL_PIXEL_FORMAT_RGBX_8888 => PixelFormat::Rgbx8888,
+            HAL_PIXEL_FORMAT_RGB_888 => PixelFormat::Rgb888,
+            HAL_PIXEL_FORMAT_RGB_565 => PixelFormat::Rgb565,
+            HAL_PIXEL_FORMAT_BGRA_8888 => PixelFormat::Bgra8888,
+            HAL_PIXEL_FORMAT_YV12 => PixelFormat::Yv12,
+            0 => PixelFormat::ImplementationDefined,
+            format => PixelFormat::Raw(format),
+        }
+    }
+
+    /// The format to try when gralloc can't allocate this one: RGBX and BGRA
+    /// fall back to RGBA, which every gralloc supports.
+    pub fn fallback(&self) -> Option<PixelFormat> {
+        match *self {
+            PixelFormat::Rgbx8888 | PixelFormat::Bgra8888 => Some(PixelFormat::Rgba8888),
+            _ => None,
+        }
+    }
+
+    /// The size of a pixel of the packed formats. None for YV12, which is
+    /// planar, and for the formats gralloc lays out as it likes.
+    pub fn bytes_per_pixel(&self) -> Option<usize> {
+        match *self {
+            PixelFormat::Rgba8888 | PixelFormat::Rgbx8888 | PixelFormat::Bgra8888 => Some(4),
+            PixelFormat::Rgb888 => Some(3),
+            PixelFormat::Rgb565 => Some(2),
+            PixelFormat::Yv12 | PixelFormat::ImplementationDefined | PixelFormat::Raw(_) => None,
+        }
+    }
+
+    /// The channel order of the packed formats, None for the others.
+    pub fn channel_order(&self) -> Option<ChannelOrder> {
+        match *self {
+            PixelFormat::Rgba8888 => Some(ChannelOrder::Rgba),
+            PixelFormat::Rgbx8888 => Some(ChannelOrder::Rgbx),
+            PixelFormat::Rgb888 => Some(ChannelOrder::Rgb),
+            PixelFormat::Rgb565 => Some(ChannelOrder::Rgb565),
+            PixelFormat::Bgra8888 => Some(ChannelOrder::Bgra),
+            PixelFormat::Yv12 | PixelFormat::ImplementationDefined | PixelFormat::Raw(_) => None,
+        }
+    }
+
+    pub fn as_gralloc(&self) -> c_int {
+        match *self {
+            PixelFormat::Rgba8888 => HAL_PIXEL_FORMAT_RGBA_8888,
+            PixelFormat::Rgbx8888 => HAL_PIXEL_FORMAT_RGBX_8888,
+            PixelFormat::Rgb888 => HAL_PIXEL_FORMAT_RGB_888,
+            PixelFormat::Rgb565 => HAL_PIXEL_FORMAT_RGB_565,
+            PixelFormat::Bgra8888 => HAL_PIXEL_FORMAT_BGRA_8888,
+            PixelFormat::Yv12 => HAL_PIXEL_FORMAT_YV12,
+            PixelFormat::ImplementationDefined => 0,
+            PixelFormat::Raw(format) => format,
+        }
+    }
+}
+
+/// The size in bytes of a `width`x`height` buffer of `format`, whose rows are
+/// `stride` pixels apart, as gralloc lays it out. None for the raw and
+/// implementation defined formats, whose layout is up to the gralloc
+/// implementation, and for a stride narrower than the buffer.
+pub fn buffer_byte_size(
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: PixelFormat,
+) -> Option<usize> {
+    if width < 0 || height < 0 || stride < width {
+        return None;
+    }
+    let (height, stride) = (height as usize, stride as usize);
+    match format {
+        // From the YV12 description in graphics.h: a Y plane followed by the
+        // Cr and Cb planes, each half as wide and high, with their rows
+        // 16-byte aligned.
+        PixelFormat::Yv12 => {
+            let c_stride = (stride / 2 + 15) & !15;
+            Some(stride * height + 2 * c_stride * (height / 2))
+        }
+        format => format
+            .bytes_per_pixel()
+            .map(|bytes| stride * height * bytes),
+    }
+}
+
 // From hardware/libhardware/include/hardware/gralloc.h
 
 #[repr(C)]
 pub struct gralloc_module {
-    common: hw_module,
-    register_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
-    unregister_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
-    lock: extern "C" fn(
+    pub common: hw_module,
+    pub register_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
+    pub unregister_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
+    pub lock: extern "C" fn(
         *const gralloc_module,
         *const native_handle,
         c_int,
         c_int,
         c_int,
         c_int,
+        c_int,
         *mut *mut c_void,
     ) -> c_int,
-    unlock: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
-    perform: extern "C" fn(*const gralloc_module, c_int, ...) -> c_int,
-    lock_ycbcr: extern "C" fn(
+    pub unlock: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
+    pub perform: unsafe extern "C" fn(*const gralloc_module, c_int, ...) -> c_int,
+    pub lock_ycbcr: extern "C" fn(
         *const gralloc_module,
         *const native_handle,
         c_int,
@@ -50,12 +186,12 @@ pub struct gralloc_module {
         c_int,
         *mut android_ycbcr,
     ) -> c_int,
-    reserved: [*mut c_void; 6],
+    pub reserved: [*mut c_void; 6],
 }
 
 #[repr(C)]
 pub struct alloc_device {
-    common: hw_device,
+    pub common: hw_device,
     pub alloc: extern "C" fn(
         *mut alloc_device,
         c_int,
@@ -67,21 +203,317 @@ pub struct alloc_device {
     ) -> c_int,
     pub free: extern "C" fn(*mut alloc_device, *const native_handle) -> c_int,
     pub dump: Option<extern "C" fn(*mut alloc_device, *mut c_char, c_int)>,
-    reserved: [*mut c_void; 7],
+    pub reserved: [*mut c_void; 7],
 }
 
-pub fn get_gralloc_module() -> *mut alloc_device {
-    let mut gralloc_mod = ptr::null();
-    let alloc_dev: *mut alloc_device;
+fn get_gralloc_hw_module() -> Result<*const hw_module, GonkGfxError> {
+    get_module(&CString::new("gralloc").unwrap())
+}
+
+fn open_alloc_device() -> Result<*mut alloc_device, GonkGfxError> {
+    let gralloc_mod = get_gralloc_hw_module()?;
     unsafe {
         let mut device = ptr::null();
-        let cstr = CString::new("gralloc").unwrap();
-        let ret1 = hw_get_module(cstr.as_ptr(), &mut gralloc_mod);
-        assert_eq!(ret1, 0, "Failed to get gralloc module!");
-        let cstr2 = CString::new("gpu0").unwrap();
-        let ret2 = ((*(*gralloc_mod).methods).open)(gralloc_mod, cstr2.as_ptr(), &mut device);
-        assert_eq!(ret2, 0, "Failed to open gpu0 on gralloc module!");
-        alloc_dev = transmute(device);
-    }
-    alloc_dev
+        let cstr = CString::new("gpu0").unwrap();
+        let ret = ((*(*gralloc_mod).methods).open)(gralloc_mod, cstr.as_ptr(), &mut device);
+        if ret != 0 {
+            error!(target: LOG_TARGET, "Failed to open gpu0 on gralloc module!");
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(transmute(device))
+    }
+}
+
+pub fn get_gralloc_module() -> *mut alloc_device {
+    open_alloc_device().expect("Failed to open the gralloc device!")
+}
+
+/// An open gralloc allocation device, closed when dropped.
+pub struct AllocDevice {
+    native: *mut alloc_device,
+}
+
+// Gralloc devices can be used from any thread, and windows share theirs with
+// the buffers they allocate.
+unsafe impl Send for AllocDevice {}
+unsafe impl Sync for AllocDevice {}
+
+impl AllocDevice {
+    pub fn new() -> Result<AllocDevice, GonkGfxError> {
+        open_alloc_device().map(|native| AllocDevice { native })
+    }
+
+    /// Like `new`, retrying while the gralloc module can't be found, see
+    /// `open_with_retry`.
+    pub fn new_with_retry(attempts: u32, delay: Duration) -> Result<AllocDevice, GonkGfxError> {
+        open_with_retry(attempts, delay, AllocDevice::new)
+    }
+
+    /// Whether gralloc can allocate a `width`x`height` buffer of `format`
+    /// for `usage`, which it finds out by allocating one and freeing it right
+    /// away. Small sizes make it cheap enough to try a few formats at startup.
+    pub fn probe(&self, width: i32, height: i32, format: PixelFormat, usage: c_int) -> bool {
+        let mut handle = ptr::null();
+        let mut stride = 0;
+        let ret = unsafe {
+            ((*self.native).alloc)(
+                self.native,
+                width,
+                height,
+                format.as_gralloc(),
+                usage,
+                &mut handle,
+                &mut stride,
+            )
+        };
+        // Some grallocs return a handle along with an error, or succeed
+        // without one: free whatever we got either way.
+        if !handle.is_null() {
+            unsafe {
+                ((*self.native).free)(self.native, handle);
+            }
+        }
+        let supported = ret == 0 && !handle.is_null() && stride > 0;
+        debug!(
+            target: LOG_TARGET,
+            "{}x{} {:?} buffers with usage {:#x} supported: {}",
+            width,
+            height,
+            format,
+            usage,
+            supported
+        );
+        supported
+    }
+
+    pub fn native(&self) -> *mut alloc_device {
+        self.native
+    }
+
+    /// Returns the gralloc device dump, if the HAL implements one.
+    pub fn dump(&self) -> Option<String> {
+        let dump = match unsafe { (*self.native).dump } {
+            Some(dump) => dump,
+            None => return None,
+        };
+        let mut buffer: [c_char; DUMP_BUFFER_SIZE] = [0; DUMP_BUFFER_SIZE];
+        unsafe {
+            dump(
+                self.native,
+                buffer.as_mut_ptr(),
+                (DUMP_BUFFER_SIZE - 1) as c_int,
+            );
+            Some(
+                CStr::from_ptr(buffer.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    }
+}
+
+impl Drop for AllocDevice {
+    fn drop(&mut self) {
+        info!(target: LOG_TARGET, "Closing gralloc device");
+        unsafe {
+            hw_device::close(&mut (*self.native).common);
+        }
+    }
+}
+
+/// The gralloc module, used to map buffers allocated elsewhere.
+#[derive(Clone, Copy)]
+pub struct GrallocModule {
+    native: *const gralloc_module,
+}
+
+impl GrallocModule {
+    pub fn new() -> Result<GrallocModule, GonkGfxError> {
+        get_gralloc_hw_module().map(|module| GrallocModule {
+            native: module as *const gralloc_module,
+        })
+    }
+
+    pub fn native(&self) -> *const gralloc_module {
+        self.native
+    }
+
+    /// Makes a buffer handle received from another process usable in this
+    /// one. This must happen before locking it.
+    pub fn register_buffer(&self, handle: *const native_handle) -> Result<(), GonkGfxError> {
+        if handle.is_null() || !unsafe { (*handle).is_valid() } {
+            error!(target: LOG_TARGET, "Refusing to register an invalid handle");
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        let ret = unsafe { ((*self.native).register_buffer)(self.native, handle) };
+        if ret != 0 {
+            error!(target: LOG_TARGET, "register_buffer failed: {}", ret);
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(())
+    }
+
+    pub fn unregister_buffer(&self, handle: *const native_handle) -> Result<(), GonkGfxError> {
+        let ret = unsafe { ((*self.native).unregister_buffer)(self.native, handle) };
+        if ret != 0 {
+            error!(target: LOG_TARGET, "unregister_buffer failed: {}", ret);
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the memory backing a buffer, e.g. to mmap it.
+    ///
+    /// AOSP defines no `perform` operations: the GET_BUFFER_SIZE some
+    /// grallocs have is a vendor opcode, numbered differently on every
+    /// vendor, and calling an unknown one is undefined. So instead this asks
+    /// the kernel for the size of the handle's first fd, the ion or dma-buf
+    /// allocation those opcodes report on. Handles without fds are
+    /// `Unsupported`.
+    pub fn buffer_size(&self, handle: *const native_handle) -> Result<usize, GonkGfxError> {
+        let fd = match handle_fds(handle).first() {
+            Some(&fd) => fd,
+            None => {
+                error!(target: LOG_TARGET, "No fd to get the buffer size from");
+                return Err(GonkGfxError::Unsupported);
+            }
+        };
+        let size = unsafe { lseek(fd, 0, SEEK_END) };
+        if size < 0 {
+            let err = errno().0;
+            error!(target: LOG_TARGET, "Failed to get the size of fd {}: {}", fd, err);
+            return Err(GonkGfxError::from_status(-err));
+        }
+        Ok(size as usize)
+    }
+
+    /// Maps the `width`x`height` top left part of a buffer for the CPU, with
+    /// `usage` made of the GRALLOC_USAGE_SW_* bits, for as long as the
+    /// returned guard lives.
+    pub fn lock(
+        &self,
+        handle: *const native_handle,
+        usage: c_int,
+        width: i32,
+        height: i32,
+    ) -> Result<LockedBuffer, GonkGfxError> {
+        let mut addr = ptr::null_mut();
+        let ret = unsafe {
+            ((*self.native).lock)(self.native, handle, usage, 0, 0, width, height, &mut addr)
+        };
+        if ret != 0 {
+            error!(target: LOG_TARGET, "lock failed: {}", ret);
+            return Err(GonkGfxError::from_status(ret));
+        }
+        if addr.is_null() {
+            error!(target: LOG_TARGET, "lock returned no address");
+            let _ = unsafe { ((*self.native).unlock)(self.native, handle) };
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        Ok(LockedBuffer {
+            module: *self,
+            handle,
+            addr,
+        })
+    }
+
+    /// Registers a handle for as long as the returned guard lives.
+    pub fn register(&self, handle: *const native_handle) -> Result<RegisteredBuffer, GonkGfxError> {
+        self.register_buffer(handle)?;
+        Ok(RegisteredBuffer {
+            module: *self,
+            handle,
+        })
+    }
+}
+
+/// A buffer handle registered with gralloc, unregistered when dropped.
+pub struct RegisteredBuffer {
+    module: GrallocModule,
+    handle: *const native_handle,
+}
+
+impl RegisteredBuffer {
+    pub fn handle(&self) -> *const native_handle {
+        self.handle
+    }
+}
+
+impl Drop for RegisteredBuffer {
+    fn drop(&mut self) {
+        let _ = self.module.unregister_buffer(self.handle);
+    }
+}
+
+/// A buffer mapped for the CPU, unlocked when dropped.
+pub struct LockedBuffer {
+    module: GrallocModule,
+    handle: *const native_handle,
+    addr: *mut c_void,
+}
+
+impl LockedBuffer {
+    /// Where the buffer's pixels start, for as long as it stays locked.
+    pub fn addr(&self) -> *mut c_void {
+        self.addr
+    }
+}
+
+impl Drop for LockedBuffer {
+    fn drop(&mut self) {
+        let ret = unsafe { ((*self.module.native).unlock)(self.module.native, self.handle) };
+        if ret != 0 {
+            error!(target: LOG_TARGET, "unlock failed: {}", ret);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_sizes() {
+        // 100 isn't a multiple of 32; gralloc pads the rows to 128 pixels.
+        assert_eq!(
+            buffer_byte_size(100, 50, 128, PixelFormat::Rgba8888),
+            Some(128 * 50 * 4)
+        );
+        assert_eq!(
+            buffer_byte_size(100, 50, 128, PixelFormat::Rgb565),
+            Some(128 * 50 * 2)
+        );
+        assert_eq!(
+            buffer_byte_size(100, 50, 100, PixelFormat::Rgba8888),
+            Some(20000)
+        );
+        assert_eq!(
+            buffer_byte_size(100, 50, 100, PixelFormat::Rgb565),
+            Some(10000)
+        );
+    }
+
+    #[test]
+    fn yv12_size() {
+        // A 112-byte Y stride, then 50-byte chroma rows padded to 64.
+        assert_eq!(
+            buffer_byte_size(100, 50, 112, PixelFormat::Yv12),
+            Some(112 * 50 + 2 * 64 * 25)
+        );
+        // Chroma rows that are already 16-byte aligned.
+        assert_eq!(
+            buffer_byte_size(64, 64, 64, PixelFormat::Yv12),
+            Some(64 * 64 + 2 * 32 * 32)
+        );
+    }
+
+    #[test]
+    fn bogus_sizes() {
+        assert_eq!(buffer_byte_size(100, 50, 96, PixelFormat::Rgba8888), None);
+        assert_eq!(buffer_byte_size(-1, 50, 128, PixelFormat::Rgba8888), None);
+        assert_eq!(
+            buffer_byte_size(100, 50, 128, PixelFormat::ImplementationDefined),
+            None
+        );
+    }
 }
@@ -4,7 +4,14 @@
 
 // hardware/libhardware/include/hardware/hardware.h
 
+use error::GonkGfxError;
 use libc::{c_char, c_int};
+use std::ffi::CStr;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+const LOG_TARGET: &'static str = "gonk-gfx::hardware";
 
 #[repr(C)]
 pub struct hw_module_methods {
@@ -13,27 +20,77 @@ pub struct hw_module_methods {
 
 #[repr(C)]
 pub struct hw_module {
-    tag: u32,
-    module_api_version: u16,
-    hal_api_version: u16,
-    id: *const c_char,
-    name: *const c_char,
-    author: *const c_char,
+    pub tag: u32,
+    pub module_api_version: u16,
+    pub hal_api_version: u16,
+    pub id: *const c_char,
+    pub name: *const c_char,
+    pub author: *const c_char,
     pub methods: *mut hw_module_methods,
-    dso: *mut u32,
-    reserved: [u32; (32 - 7)],
+    pub dso: *mut u32,
+    pub reserved: [u32; (32 - 7)],
 }
 
 #[repr(C)]
 pub struct hw_device {
-    tag: u32,
+    pub tag: u32,
     pub version: u32,
-    module: *mut hw_module,
-    reserved: [u32; 12],
-    close: extern "C" fn(*mut hw_device) -> c_int,
+    pub module: *mut hw_module,
+    pub reserved: [u32; 12],
+    pub close: extern "C" fn(*mut hw_device) -> c_int,
+}
+
+impl hw_device {
+    /// Closes a device opened through its module's `open` method.
+    pub unsafe fn close(device: *mut hw_device) -> c_int {
+        ((*device).close)(device)
+    }
 }
 
+#[cfg(feature = "stub-backend")]
+pub use stub::hw_get_module;
+
+#[cfg(not(feature = "stub-backend"))]
 #[link(name = "hardware")]
 extern "C" {
     pub fn hw_get_module(id: *const c_char, module: *mut *const hw_module) -> c_int;
 }
+
+/// Loads the HAL module `id`. A missing module is `ModuleNotFound`, and the
+/// module pointer is only handed out when the HAL actually set it.
+pub fn get_module(id: &CStr) -> Result<*const hw_module, GonkGfxError> {
+    let mut module = ptr::null();
+    let ret = unsafe { hw_get_module(id.as_ptr(), &mut module) };
+    if ret != 0 {
+        error!(target: LOG_TARGET, "Failed to get the {:?} module: {}", id, ret);
+        return Err(GonkGfxError::from_status(ret));
+    }
+    if module.is_null() {
+        error!(target: LOG_TARGET, "No {:?} module", id);
+        return Err(GonkGfxError::ModuleNotFound);
+    }
+    Ok(module)
+}
+
+/// Calls `open` up to `attempts` times, `delay` apart, for as long as it
+/// fails with `ModuleNotFound`. On cold boot the graphics stack can start
+/// before the vendor HALs are in place. Other errors are returned right away.
+pub fn open_with_retry<T, F>(attempts: u32, delay: Duration, mut open: F) -> Result<T, GonkGfxError>
+where
+    F: FnMut() -> Result<T, GonkGfxError>,
+{
+    let mut attempt = 1;
+    loop {
+        match open() {
+            Err(GonkGfxError::ModuleNotFound) if attempt < attempts => {
+                warn!(
+                    target: LOG_TARGET,
+                    "HAL module not ready, retrying in {:?} ({}/{})", delay, attempt, attempts
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -4,12 +4,21 @@
 
 //! A wrapper around the hwc device
 
+use error::GonkGfxError;
+use euclid::{self, Rect};
 use gonk_gfx::*;
 use hardware::*;
 use libc::{c_char, c_int, c_void, size_t};
-use std::ffi::CString;
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
 use std::mem::transmute;
 use std::ptr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const LOG_TARGET: &'static str = "gonk-gfx::hwc";
 
 // From hardware/libhardware/include/hardware/hwcomposer.h
 
@@ -27,6 +36,12 @@ pub const HWC_POWER_MODE_DOZE_SUSPEND: c_int = 3;
 
 pub const HWC_GEOMETRY_CHANGED: u32 = 1;
 
+pub const HWC_TRANSFORM_ROT_90: c_int = 4;
+pub const HWC_TRANSFORM_ROT_180: c_int = 3;
+pub const HWC_TRANSFORM_ROT_270: c_int = 7;
+
+pub const HWC_EVENT_VSYNC: c_int = 0;
+
 pub const HWC_DISPLAY_PRIMARY: c_int = 0;
 pub const HWC_DISPLAY_EXTERNAL: c_int = 1; // HDMI, DP, etc.
 pub const HWC_DISPLAY_VIRTUAL: c_int = 2;
@@ -34,10 +49,37 @@ pub const HWC_DISPLAY_VIRTUAL: c_int = 2;
 pub const HWC_NUM_PHYSICAL_DISPLAY_TYPES: usize = 2;
 pub const HWC_NUM_DISPLAY_TYPES: usize = 3;
 
+// Same limit as SurfaceFlinger uses when asking for display configs.
+pub const MAX_DISPLAY_CONFIGS: usize = 128;
+
+// Size of the buffer handed to the HAL dump hooks.
+pub const DUMP_BUFFER_SIZE: usize = 4096;
+
 pub const HWC_BLENDING_NONE: c_int = 0x0100;
 pub const HWC_BLENDING_PREMULT: c_int = 0x0105;
 pub const HWC_BLENDING_COVERAGE: c_int = 0x0405;
 
+/// How a layer is blended with the ones below it, the HWC_BLENDING_* values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blending {
+    /// Opaque, the layer's alpha is ignored.
+    None,
+    /// The layer's colors are premultiplied by its alpha.
+    Premult,
+    /// The layer's colors are not premultiplied.
+    Coverage,
+}
+
+impl Blending {
+    pub fn as_hwc(&self) -> c_int {
+        match *self {
+            Blending::None => HWC_BLENDING_NONE,
+            Blending::Premult => HWC_BLENDING_PREMULT,
+            Blending::Coverage => HWC_BLENDING_COVERAGE,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct hwc_composer_device {
     pub common: hw_device,
@@ -49,24 +91,42 @@ pub struct hwc_composer_device {
     pub set_power_mode: extern "C" fn(*mut hwc_composer_device, c_int, c_int) -> c_int,
     pub query: extern "C" fn(*mut hwc_composer_device, c_int, *mut c_int) -> c_int,
     pub register_procs: extern "C" fn(*mut hwc_composer_device, *const hwc_procs),
-    pub dump: extern "C" fn(*mut hwc_composer_device, *const c_char, c_int),
+    pub dump: extern "C" fn(*mut hwc_composer_device, *mut c_char, c_int),
     pub get_display_configs:
         extern "C" fn(*mut hwc_composer_device, c_int, *mut u32, *mut size_t) -> c_int,
     pub get_display_attributes:
         extern "C" fn(*mut hwc_composer_device, c_int, u32, *const u32, *mut i32) -> c_int,
-    reserved: [*mut c_void; 4],
+    // The next three hooks are only there from HWC 1.4 on, and are null before.
+    pub get_active_config: Option<extern "C" fn(*mut hwc_composer_device, c_int) -> c_int>,
+    pub set_active_config: Option<extern "C" fn(*mut hwc_composer_device, c_int, c_int) -> c_int>,
+    pub set_cursor_position_async:
+        Option<extern "C" fn(*mut hwc_composer_device, c_int, c_int, c_int) -> c_int>,
+    pub reserved: [*mut c_void; 1],
 }
 
 #[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct hwc_color {
-    r: u8,
-    g: u8,
-    b: u8,
-    a: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl hwc_color {
+    /// In hwc_layer, the background color shares a union with the handle
+    /// and starts where it does.
+    pub fn as_handle(&self) -> *const native_handle {
+        let mut handle: *const native_handle = ptr::null();
+        unsafe {
+            *(&mut handle as *mut _ as *mut hwc_color) = *self;
+        }
+        handle
+    }
 }
 
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct hwc_rect {
     pub left: c_int,
     pub top: c_int,
@@ -74,8 +134,55 @@ pub struct hwc_rect {
     pub bottom: c_int,
 }
 
+impl hwc_rect {
+    pub fn new(left: c_int, top: c_int, right: c_int, bottom: c_int) -> hwc_rect {
+        hwc_rect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    pub fn from_size(width: c_int, height: c_int) -> hwc_rect {
+        hwc_rect::new(0, 0, width, height)
+    }
+
+    /// The part of this rect within `other`, None if they don't overlap.
+    pub fn intersection(&self, other: &hwc_rect) -> Option<hwc_rect> {
+        let rect = hwc_rect::new(
+            self.left.max(other.left),
+            self.top.max(other.top),
+            self.right.min(other.right),
+            self.bottom.min(other.bottom),
+        );
+        if rect.left < rect.right && rect.top < rect.bottom {
+            Some(rect)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<Rect<i32>> for hwc_rect {
+    fn from(rect: Rect<i32>) -> hwc_rect {
+        hwc_rect::new(rect.min_x(), rect.min_y(), rect.max_x(), rect.max_y())
+    }
+}
+
+impl From<hwc_rect> for Rect<i32> {
+    fn from(rect: hwc_rect) -> Rect<i32> {
+        euclid::rect(
+            rect.left,
+            rect.top,
+            rect.right - rect.left,
+            rect.bottom - rect.top,
+        )
+    }
+}
+
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct hwc_frect {
     pub left: f32,
     pub top: f32,
@@ -83,6 +190,78 @@ pub struct hwc_frect {
     pub bottom: f32,
 }
 
+impl hwc_frect {
+    pub fn new(left: f32, top: f32, right: f32, bottom: f32) -> hwc_frect {
+        hwc_frect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    pub fn from_size(width: f32, height: f32) -> hwc_frect {
+        hwc_frect::new(0.0, 0.0, width, height)
+    }
+
+    /// The smallest integer rectangle containing this one, so a sub-pixel
+    /// crop keeps its partially covered pixels instead of losing them.
+    pub fn round_out(&self) -> hwc_rect {
+        hwc_rect::new(
+            self.left.floor() as c_int,
+            self.top.floor() as c_int,
+            self.right.ceil() as c_int,
+            self.bottom.ceil() as c_int,
+        )
+    }
+
+    /// Each edge rounded to the nearest pixel.
+    pub fn round(&self) -> hwc_rect {
+        hwc_rect::new(
+            self.left.round() as c_int,
+            self.top.round() as c_int,
+            self.right.round() as c_int,
+            self.bottom.round() as c_int,
+        )
+    }
+}
+
+impl From<hwc_rect> for hwc_frect {
+    fn from(rect: hwc_rect) -> hwc_frect {
+        hwc_frect::new(
+            rect.left as f32,
+            rect.top as f32,
+            rect.right as f32,
+            rect.bottom as f32,
+        )
+    }
+}
+
+impl From<Rect<f32>> for hwc_frect {
+    fn from(rect: Rect<f32>) -> hwc_frect {
+        hwc_frect::new(rect.min_x(), rect.min_y(), rect.max_x(), rect.max_y())
+    }
+}
+
+impl From<hwc_frect> for Rect<f32> {
+    fn from(rect: hwc_frect) -> Rect<f32> {
+        euclid::rect(
+            rect.left,
+            rect.top,
+            rect.right - rect.left,
+            rect.bottom - rect.top,
+        )
+    }
+}
+
+// source_crop is integer before HWC 1.3, and float from 1.3 on.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union hwc_source_crop {
+    pub source_crop_i: hwc_rect,
+    pub source_crop_f: hwc_frect,
+}
+
 #[repr(C)]
 pub struct hwc_region {
     pub num_rects: i32,
@@ -105,7 +284,7 @@ pub struct hwc_layer {
     pub handle: *const native_handle,
     pub transform: u32,
     pub blending: i32,
-    pub source_crop: hwc_frect, // If HWC 1.3, then this takes floats
+    pub source_crop: hwc_source_crop,
     pub display_frame: hwc_rect,
     pub visible_region_screen: hwc_region,
     pub acquire_fence_fd: c_int,
@@ -123,18 +302,72 @@ pub struct hwc_display_contents {
     pub outbuf_acquire_fence_fd: c_int,
     pub flags: u32,
     pub num_hw_layers: size_t,
-    pub hw_layers: [hwc_layer; 2],
+    // A flexible array in C, sized for the most layers we hand the HWC.
+    pub hw_layers: [hwc_layer; 4],
 }
 
 #[repr(C)]
 pub struct hwc_procs {
-    invalidate: extern "C" fn(*const hwc_procs),
-    vsync: extern "C" fn(*const hwc_procs, c_int, i64),
-    hotplug: extern "C" fn(*const hwc_procs, c_int, c_int),
+    pub invalidate: extern "C" fn(*const hwc_procs),
+    pub vsync: extern "C" fn(*const hwc_procs, c_int, i64),
+    pub hotplug: extern "C" fn(*const hwc_procs, c_int, c_int),
+}
+
+/// Callbacks from the HWC, see `HwcDevice::events`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HwcEvent {
+    /// The HWC wants the screen to be redrawn.
+    Invalidate,
+    /// `timestamp_ns` is on the CLOCK_MONOTONIC timeline.
+    Vsync { display: c_int, timestamp_ns: i64 },
+    Hotplug { display: c_int, connected: bool },
+}
+
+// What we register with the HWC: the procs, which the callbacks get back a
+// pointer to, followed by where to send the events. The HWC calls us from
+// its own threads.
+#[repr(C)]
+struct HwcProcs {
+    procs: hwc_procs,
+    sender: Mutex<Sender<HwcEvent>>,
 }
 
-#[derive(PartialEq)]
+impl HwcProcs {
+    fn send(procs: *const hwc_procs, event: HwcEvent) {
+        let procs = unsafe { &*(procs as *const HwcProcs) };
+        // Nobody listening anymore is fine.
+        let _ = procs.sender.lock().unwrap().send(event);
+    }
+}
+
+extern "C" fn invalidate_proc(procs: *const hwc_procs) {
+    HwcProcs::send(procs, HwcEvent::Invalidate);
+}
+
+extern "C" fn vsync_proc(procs: *const hwc_procs, display: c_int, timestamp: i64) {
+    HwcProcs::send(
+        procs,
+        HwcEvent::Vsync {
+            display,
+            timestamp_ns: timestamp,
+        },
+    );
+}
+
+extern "C" fn hotplug_proc(procs: *const hwc_procs, display: c_int, connected: c_int) {
+    HwcProcs::send(
+        procs,
+        HwcEvent::Hotplug {
+            display,
+            connected: connected != 0,
+        },
+    );
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HwcApiVersion {
+    Hwc1_1,
+    Hwc1_2,
     Hwc1_3,
     Hwc1_4,
     Hwc1_5,
@@ -146,8 +379,18 @@ impl HwcApiVersion {
         (((maj & 0xff) << 24) | ((min & 0xff) << 16) | (1 & 0xffff))
     }
 
+    /// The major version of a `hw_device.version`, e.g. 2 for the HWC2
+    /// devices of Android 7 on.
+    pub fn major(version: u32) -> u32 {
+        version >> 24
+    }
+
     pub fn from(version: u32) -> Option<HwcApiVersion> {
-        if HwcApiVersion::hwc_api_version(1, 3) == version {
+        if HwcApiVersion::hwc_api_version(1, 1) == version {
+            Some(HwcApiVersion::Hwc1_1)
+        } else if HwcApiVersion::hwc_api_version(1, 2) == version {
+            Some(HwcApiVersion::Hwc1_2)
+        } else if HwcApiVersion::hwc_api_version(1, 3) == version {
             Some(HwcApiVersion::Hwc1_3)
         } else if HwcApiVersion::hwc_api_version(1, 4) == version {
             Some(HwcApiVersion::Hwc1_4)
@@ -157,24 +400,77 @@ impl HwcApiVersion {
             None
         }
     }
+
+    /// Whether layers take a float (`hwc_frect`) source crop.
+    pub fn has_float_source_crop(&self) -> bool {
+        match *self {
+            HwcApiVersion::Hwc1_1 | HwcApiVersion::Hwc1_2 => false,
+            _ => true,
+        }
+    }
+}
+
+/// The HWC_POWER_MODE_* values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerMode {
+    Off,
+    /// Low power, still showing content, e.g. for an ambient display.
+    Doze,
+    Normal,
+    /// Doze, with the CPU allowed to suspend.
+    DozeSuspend,
+}
+
+impl PowerMode {
+    pub fn as_hwc(&self) -> c_int {
+        match *self {
+            PowerMode::Off => HWC_POWER_MODE_OFF,
+            PowerMode::Doze => HWC_POWER_MODE_DOZE,
+            PowerMode::Normal => HWC_POWER_MODE_NORMAL,
+            PowerMode::DozeSuspend => HWC_POWER_MODE_DOZE_SUSPEND,
+        }
+    }
+}
+
+/// The attributes of a display config, see `HwcDevice::display_attributes`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayAttributes {
+    pub width: i32,
+    pub height: i32,
+    pub vsync_period_ns: i32,
+    /// In dots per inch; the HWC reports them times 1000.
+    pub dpi_x: i32,
+    pub dpi_y: i32,
+}
+
+/// A display mode, as reported by `get_display_attributes`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayConfig {
+    /// Opaque HWC handle for this config, to hand back to the HWC.
+    pub id: u32,
+    /// Position in the list returned by `display_configs`, which is what
+    /// setActiveConfig takes.
+    pub index: usize,
+    pub width: i32,
+    pub height: i32,
+    pub vsync_period_ns: i32,
+    pub dpi_x: i32,
+    pub dpi_y: i32,
 }
 
 pub struct HwcDevice {
     native: *mut hwc_composer_device,
     version: HwcApiVersion,
+    // Registered on the first events() call, and kept until the device is
+    // closed since the HWC has no way to unregister them.
+    procs: Option<Box<HwcProcs>>,
+    // Whether set_display() holds a set_autosuspend(false).
+    autosuspend_disabled: Cell<bool>,
 }
 
 impl HwcDevice {
-    pub fn new() -> Option<HwcDevice> {
-        let mut hwc_mod = ptr::null();
-        unsafe {
-            let cstr = CString::new("hwcomposer").unwrap();
-            let ret = hw_get_module(cstr.as_ptr(), &mut hwc_mod);
-            if ret != 0 {
-                error!("Failed to get HWC module!");
-                return None;
-            }
-        }
+    pub fn new() -> Result<HwcDevice, GonkGfxError> {
+        let hwc_mod = get_module(&CString::new("hwcomposer").unwrap())?;
 
         let hwc_device: *mut hwc_composer_device;
         unsafe {
@@ -182,21 +478,45 @@ impl HwcDevice {
             let cstr = CString::new("composer").unwrap();
             let ret = ((*(*hwc_mod).methods).open)(hwc_mod, cstr.as_ptr(), &mut device);
             if ret != 0 {
-                error!("Failed to get HWC device!");
-                return None;
+                error!(target: LOG_TARGET, "Failed to get HWC device!");
+                return Err(GonkGfxError::from_status(ret));
             }
             hwc_device = transmute(device);
 
-            match HwcApiVersion::from((*hwc_device).common.version) {
-                None => None,
-                Some(version) => Some(HwcDevice {
+            // Only the hw_device header is shared with HWC2 devices, whose
+            // functions are looked up through getFunction() rather than
+            // sitting in the device. Nothing past the header may be called.
+            let version = (*hwc_device).common.version;
+            match HwcApiVersion::from(version) {
+                None if HwcApiVersion::major(version) >= 2 => {
+                    error!(
+                        target: LOG_TARGET,
+                        "HWC2 device (version {:x}), only HWC 1.1 to 1.5 are supported", version
+                    );
+                    hw_device::close(&mut (*hwc_device).common);
+                    Err(GonkGfxError::Unsupported)
+                }
+                None => {
+                    error!(target: LOG_TARGET, "Unsupported HWC version {:x}", version);
+                    hw_device::close(&mut (*hwc_device).common);
+                    Err(GonkGfxError::Unsupported)
+                }
+                Some(version) => Ok(HwcDevice {
                     native: hwc_device,
                     version,
+                    procs: None,
+                    autosuspend_disabled: Cell::new(false),
                 }),
             }
         }
     }
 
+    /// Like `new`, retrying while the HWC module can't be found, see
+    /// `open_with_retry`.
+    pub fn new_with_retry(attempts: u32, delay: Duration) -> Result<HwcDevice, GonkGfxError> {
+        open_with_retry(attempts, delay, HwcDevice::new)
+    }
+
     pub fn get_dimensions_and_dpi(&self) -> (i32, i32, i32) {
         let attrs: [u32; 4] = [
             HWC_DISPLAY_WIDTH,
@@ -219,16 +539,180 @@ impl HwcDevice {
         (values[0], values[1], values[2] / 1000)
     }
 
-    pub fn set_display(&self, enable: bool) {
-        if enable {
-            unsafe {
-                autosuspend_disable();
+    /// Lists the modes `display` supports, in the order the HWC reports
+    /// them. Before HWC 1.4 the first one is the only mode in use.
+    pub fn display_configs(&self, display: c_int) -> Result<Vec<DisplayConfig>, GonkGfxError> {
+        let mut ids: [u32; MAX_DISPLAY_CONFIGS] = [0; MAX_DISPLAY_CONFIGS];
+        let mut num_configs: size_t = MAX_DISPLAY_CONFIGS;
+        let ret = unsafe {
+            ((*self.native).get_display_configs)(
+                self.native,
+                display,
+                ids.as_mut_ptr(),
+                &mut num_configs,
+            )
+        };
+        if ret != 0 {
+            error!(target: LOG_TARGET, "Failed to get configs of display {}: {}", display, ret);
+            return Err(GonkGfxError::from_status(ret));
+        }
+        let num_configs = num_configs.min(MAX_DISPLAY_CONFIGS);
+
+        let mut configs = Vec::with_capacity(num_configs);
+        for (index, &id) in ids[..num_configs].iter().enumerate() {
+            let attributes = self.display_attributes(display, id)?;
+            configs.push(DisplayConfig {
+                id,
+                index,
+                width: attributes.width,
+                height: attributes.height,
+                vsync_period_ns: attributes.vsync_period_ns,
+                dpi_x: attributes.dpi_x,
+                dpi_y: attributes.dpi_y,
+            });
+        }
+        Ok(configs)
+    }
+
+    /// Queries the size, refresh rate and density of `config`, one of the
+    /// ids from `display_configs`, on `display`.
+    pub fn display_attributes(
+        &self,
+        display: c_int,
+        config: u32,
+    ) -> Result<DisplayAttributes, GonkGfxError> {
+        // The HWC fills `values` in step with `attrs`, up to the terminating
+        // HWC_DISPLAY_NO_ATTRIBUTE.
+        let attrs: [u32; 6] = [
+            HWC_DISPLAY_WIDTH,
+            HWC_DISPLAY_HEIGHT,
+            HWC_DISPLAY_VSYNC_PERIOD,
+            HWC_DISPLAY_DPI_X,
+            HWC_DISPLAY_DPI_Y,
+            HWC_DISPLAY_NO_ATTRIBUTE,
+        ];
+        let mut values: [i32; 6] = [0; 6];
+        let ret = unsafe {
+            ((*self.native).get_display_attributes)(
+                self.native,
+                display,
+                config,
+                attrs.as_ptr(),
+                values.as_mut_ptr(),
+            )
+        };
+        if ret != 0 {
+            error!(
+                target: LOG_TARGET,
+                "Failed to get attributes of config {} on display {}: {}", config, display, ret
+            );
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(DisplayAttributes {
+            width: values[0],
+            height: values[1],
+            vsync_period_ns: values[2],
+            dpi_x: values[3] / 1000,
+            dpi_y: values[4] / 1000,
+        })
+    }
+
+    /// Switches `display` to one of the modes from `display_configs`.
+    ///
+    /// The HWC doesn't touch the windows drawing to that display: call
+    /// `GonkNativeWindow::set_default_dimensions` with the new mode's size so
+    /// their buffers match it again.
+    pub fn set_active_config(
+        &self,
+        display: c_int,
+        config: &DisplayConfig,
+    ) -> Result<(), GonkGfxError> {
+        let set_active_config = match self.version {
+            HwcApiVersion::Hwc1_4 | HwcApiVersion::Hwc1_5 => unsafe {
+                (*self.native).set_active_config
+            },
+            _ => None,
+        };
+        let set_active_config = match set_active_config {
+            Some(f) => f,
+            None => {
+                error!(
+                    target: LOG_TARGET,
+                    "No mode switching support on HWC {:?}", self.version
+                );
+                return Err(GonkGfxError::Unsupported);
             }
+        };
+
+        info!(
+            target: LOG_TARGET,
+            "Switching display {} to {}x{} (config {})",
+            display,
+            config.width,
+            config.height,
+            config.id
+        );
+        let ret = set_active_config(self.native, display, config.index as c_int);
+        if ret != 0 {
+            error!(target: LOG_TARGET, "Failed to set active config: {}", ret);
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(())
+    }
+
+    /// Returns a channel receiving the HWC's vsync, hotplug and invalidate
+    /// callbacks. Calling it again moves the events to the new channel.
+    pub fn events(&mut self) -> Receiver<HwcEvent> {
+        let (sender, receiver) = channel();
+        if let Some(ref procs) = self.procs {
+            *procs.sender.lock().unwrap() = sender;
+            return receiver;
+        }
+
+        let procs = Box::new(HwcProcs {
+            procs: hwc_procs {
+                invalidate: invalidate_proc,
+                vsync: vsync_proc,
+                hotplug: hotplug_proc,
+            },
+            sender: Mutex::new(sender),
+        });
+        unsafe {
+            ((*self.native).register_procs)(self.native, &procs.procs);
         }
+        self.procs = Some(procs);
+        receiver
+    }
 
-        // If the version is 1.3, we actually are using the blank()
-        // method behing the scene.
-        let mode = if self.version == HwcApiVersion::Hwc1_3 {
+    /// Sends an `HwcEvent::Invalidate` to the `events` receiver as if the
+    /// HWC asked for it, e.g. to have the render loop recomposite after a
+    /// cursor moved over a video. Does nothing before `events` was called.
+    pub fn invalidate(&self) {
+        if let Some(ref procs) = self.procs {
+            invalidate_proc(&procs.procs);
+        }
+    }
+
+    /// Turns the vsync events of `display` on or off. They are only
+    /// delivered once `events` was called.
+    pub fn set_vsync_enabled(&self, display: c_int, enabled: bool) -> Result<(), GonkGfxError> {
+        set_vsync_enabled(self.native, display, enabled)
+    }
+
+    pub fn set_display(&self, enable: bool) {
+        // A single hold, however often the display is turned on.
+        if enable && !self.autosuspend_disabled.get() {
+            set_autosuspend(false);
+            self.autosuspend_disabled.set(true);
+        }
+
+        // Before 1.4, we actually are using the blank() method behind
+        // the scene.
+        let blank = match self.version {
+            HwcApiVersion::Hwc1_4 | HwcApiVersion::Hwc1_5 => false,
+            _ => true,
+        };
+        let mode = if blank {
             if enable {
                 0
             } else {
@@ -246,13 +730,137 @@ impl HwcDevice {
         }
 
         if !enable {
-            unsafe {
-                autosuspend_enable();
+            if self.autosuspend_disabled.get() {
+                set_autosuspend(true);
+                self.autosuspend_disabled.set(false);
+            } else if AUTOSUSPEND_HOLDS.load(Ordering::SeqCst) == 0 {
+                // Nobody keeps the device up: let it sleep, whoever
+                // disabled autosuspend.
+                unsafe {
+                    autosuspend_enable();
+                }
             }
         }
     }
 
+    /// Sets the power mode of `display`, leaving autosuspend alone unlike
+    /// `set_display`. Before HWC 1.4 the hook is blank(), which only knows
+    /// on and off, so the doze modes are `Unsupported`.
+    pub fn set_power_mode(&self, display: c_int, mode: PowerMode) -> Result<(), GonkGfxError> {
+        let value = self.power_mode_value(mode)?;
+        let ret = unsafe { ((*self.native).set_power_mode)(self.native, display, value) };
+        if ret != 0 {
+            error!(target: LOG_TARGET, "Failed to set power mode {:?}: {}", mode, ret);
+            return Err(GonkGfxError::from_status(ret));
+        }
+        Ok(())
+    }
+
+    // What the set_power_mode hook takes for `mode` on this HWC.
+    pub(crate) fn power_mode_value(&self, mode: PowerMode) -> Result<c_int, GonkGfxError> {
+        match self.version {
+            HwcApiVersion::Hwc1_4 | HwcApiVersion::Hwc1_5 => Ok(mode.as_hwc()),
+            _ => match mode {
+                PowerMode::Normal => Ok(0),
+                PowerMode::Off => Ok(1),
+                _ => Err(GonkGfxError::Unsupported),
+            },
+        }
+    }
+
+    /// The transform the producers of `display` should pre-rotate their
+    /// buffers with, 0 if none. See `display_transform_hint`.
+    pub fn transform_hint(&self, display: c_int) -> c_int {
+        display_transform_hint(display)
+    }
+
     pub fn native(&self) -> *mut hwc_composer_device {
         self.native
     }
+
+    pub fn version(&self) -> HwcApiVersion {
+        self.version
+    }
+
+    /// Returns the HWC's own textual dump of its state (layer assignments,
+    /// overlay usage...), useful in bug reports.
+    pub fn dump(&self) -> String {
+        let mut buffer: [c_char; DUMP_BUFFER_SIZE] = [0; DUMP_BUFFER_SIZE];
+        unsafe {
+            ((*self.native).dump)(
+                self.native,
+                buffer.as_mut_ptr(),
+                (DUMP_BUFFER_SIZE - 1) as c_int,
+            );
+            CStr::from_ptr(buffer.as_ptr())
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+}
+
+// From system/core/include/cutils/properties.h
+const PROPERTY_VALUE_MAX: usize = 92;
+
+#[cfg(feature = "stub-backend")]
+use stub::property_get;
+
+#[cfg(not(feature = "stub-backend"))]
+#[link(name = "cutils")]
+extern "C" {
+    fn property_get(key: *const c_char, value: *mut c_char, default_value: *const c_char) -> c_int;
+}
+
+/// The HWC 1.x display attributes don't include the panel orientation, so
+/// like SurfaceFlinger we take it from `ro.sf.hwrotation`, in degrees. Only
+/// the primary display can be mounted rotated.
+pub fn display_transform_hint(display: c_int) -> c_int {
+    if display != HWC_DISPLAY_PRIMARY {
+        return 0;
+    }
+    let key = CString::new("ro.sf.hwrotation").unwrap();
+    let default = CString::new("0").unwrap();
+    let mut value: [c_char; PROPERTY_VALUE_MAX] = [0; PROPERTY_VALUE_MAX];
+    let rotation = unsafe {
+        property_get(key.as_ptr(), value.as_mut_ptr(), default.as_ptr());
+        CStr::from_ptr(value.as_ptr()).to_string_lossy().into_owned()
+    };
+    match rotation.trim() {
+        "0" | "" => 0,
+        "90" => HWC_TRANSFORM_ROT_90,
+        "180" => HWC_TRANSFORM_ROT_180,
+        "270" => HWC_TRANSFORM_ROT_270,
+        other => {
+            warn!(target: LOG_TARGET, "Ignoring ro.sf.hwrotation={}", other);
+            0
+        }
+    }
+}
+
+// Also used from the vsync scheduler thread, like SurfaceFlinger's
+// EventThread does, while the device is busy composing elsewhere.
+pub(crate) fn set_vsync_enabled(
+    hwc: *mut hwc_composer_device,
+    display: c_int,
+    enabled: bool,
+) -> Result<(), GonkGfxError> {
+    trace!(target: LOG_TARGET, "Vsync on display {}: {}", display, enabled);
+    let ret = unsafe { ((*hwc).event_control)(hwc, display, HWC_EVENT_VSYNC, enabled as c_int) };
+    if ret != 0 {
+        error!(target: LOG_TARGET, "Failed to set vsync to {}: {}", enabled, ret);
+        return Err(GonkGfxError::from_status(ret));
+    }
+    Ok(())
+}
+
+impl Drop for HwcDevice {
+    fn drop(&mut self) {
+        info!(target: LOG_TARGET, "Closing HWC device");
+        if self.autosuspend_disabled.get() {
+            set_autosuspend(true);
+        }
+        unsafe {
+            hw_device::close(&mut (*self.native).common);
+        }
+    }
 }
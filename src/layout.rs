@@ -0,0 +1,124 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Build time checks that the structs we share with the HALs and the kernel
+// have the sizes and alignments of their C counterparts. A wrong field type or
+// padding fails the build here, instead of corrupting the HWC's memory at run
+// time. Field offsets can't be computed in a constant, so the tests check the
+// ones the HWC reads and writes.
+
+// Fails to build unless `$ty` is `$size` bytes, as the array lengths differ
+// otherwise.
+macro_rules! assert_size {
+    ($name:ident, $ty:ty, $size:expr) => {
+        #[allow(dead_code)]
+        const $name: [(); $size] = [(); ::std::mem::size_of::<$ty>()];
+    };
+}
+
+// Same for the alignment of `$ty`.
+macro_rules! assert_align {
+    ($name:ident, $ty:ty, $align:expr) => {
+        #[allow(dead_code)]
+        const $name: [(); $align] = [(); ::std::mem::align_of::<$ty>()];
+    };
+}
+
+// The structs without pointers or longs have the same layout on every
+// target.
+mod fixed {
+    use gonk_gfx::*;
+    use hwc::*;
+
+    // cutils/native_handle.h
+    assert_size!(NATIVE_HANDLE, native_handle, 12);
+    assert_align!(NATIVE_HANDLE_ALIGN, native_handle, 4);
+
+    // hardware/hwcomposer.h
+    assert_size!(HWC_RECT, hwc_rect, 16);
+    assert_align!(HWC_RECT_ALIGN, hwc_rect, 4);
+    assert_size!(HWC_FRECT, hwc_frect, 16);
+    assert_align!(HWC_FRECT_ALIGN, hwc_frect, 4);
+    assert_size!(HWC_SOURCE_CROP, hwc_source_crop, 16);
+    assert_align!(HWC_SOURCE_CROP_ALIGN, hwc_source_crop, 4);
+}
+
+// The other HAL structs mirror the 32-bit ARM headers, which is what Gonk
+// devices run. Everything in there is 4-byte aligned. The LP64 headers widen
+// the reserved words of hw_module and friends, which the structs here don't
+// follow, so 64-bit targets don't check them.
+#[cfg(target_pointer_width = "32")]
+mod hal {
+    use gonk_gfx::*;
+    use gralloc::*;
+    use hardware::*;
+    use hwc::*;
+
+    // hardware/hardware.h
+    assert_size!(HW_MODULE, hw_module, 128);
+    assert_align!(HW_MODULE_ALIGN, hw_module, 4);
+    assert_size!(HW_DEVICE, hw_device, 64);
+    assert_align!(HW_DEVICE_ALIGN, hw_device, 4);
+
+    // hardware/gralloc.h
+    assert_size!(GRALLOC_MODULE, gralloc_module, 176);
+    assert_align!(GRALLOC_MODULE_ALIGN, gralloc_module, 4);
+    assert_size!(ALLOC_DEVICE, alloc_device, 104);
+    assert_align!(ALLOC_DEVICE_ALIGN, alloc_device, 4);
+    assert_size!(ANDROID_YCBCR, android_ycbcr, 56);
+    assert_align!(ANDROID_YCBCR_ALIGN, android_ycbcr, 4);
+
+    // hardware/hwcomposer.h, as of HWC 1.4.
+    assert_size!(HWC_COMPOSER_DEVICE, hwc_composer_device, 116);
+    assert_align!(HWC_COMPOSER_DEVICE_ALIGN, hwc_composer_device, 4);
+    assert_size!(HWC_REGION, hwc_region, 8);
+    assert_align!(HWC_REGION_ALIGN, hwc_region, 4);
+    assert_size!(HWC_LAYER, hwc_layer, 96);
+    assert_align!(HWC_LAYER_ALIGN, hwc_layer, 4);
+    // The header, then the layers we have room for.
+    assert_size!(HWC_DISPLAY_CONTENTS, hwc_display_contents, 20 + 4 * 96);
+    assert_align!(HWC_DISPLAY_CONTENTS_ALIGN, hwc_display_contents, 4);
+    assert_size!(HWC_PROCS, hwc_procs, 12);
+    assert_align!(HWC_PROCS_ALIGN, hwc_procs, 4);
+
+    // system/window.h
+    assert_size!(ANATIVE_BASE, ANativeBase, 32);
+    assert_align!(ANATIVE_BASE_ALIGN, ANativeBase, 4);
+    assert_size!(ANATIVE_WINDOW_BUFFER, ANativeWindowBuffer, 96);
+    assert_align!(ANATIVE_WINDOW_BUFFER_ALIGN, ANativeWindowBuffer, 4);
+    assert_size!(ANATIVE_WINDOW, ANativeWindow, 108);
+    assert_align!(ANATIVE_WINDOW_ALIGN, ANativeWindow, 4);
+}
+
+#[cfg(test)]
+mod tests {
+    use hwc::hwc_layer;
+    use std::mem::zeroed;
+
+    // How far `$field` is into `$value`.
+    macro_rules! offset_of {
+        ($value:expr, $field:ident) => {
+            &$value.$field as *const _ as usize - &$value as *const _ as usize
+        };
+    }
+
+    // The fields up to the fences line up with hwc_layer_1_t on LP64 too, as
+    // the handle and the regions' pointers are aligned the same.
+    #[cfg(target_pointer_width = "32")]
+    const HWC_LAYER_OFFSETS: [usize; 4] = [0, 12, 64, 68];
+    #[cfg(target_pointer_width = "64")]
+    const HWC_LAYER_OFFSETS: [usize; 4] = [0, 16, 80, 84];
+
+    #[test]
+    fn hwc_layer_offsets() {
+        let layer: hwc_layer = unsafe { zeroed() };
+        let offsets = [
+            offset_of!(layer, composition_type),
+            offset_of!(layer, handle),
+            offset_of!(layer, acquire_fence_fd),
+            offset_of!(layer, release_fence_fd),
+        ];
+        assert_eq!(offsets, HWC_LAYER_OFFSETS);
+    }
+}
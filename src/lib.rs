@@ -4,6 +4,8 @@
 
 // For size_of::<linux_input_event>() in input.rs
 #![feature(const_size_of)]
+// For the alignment checks in layout.rs
+#![feature(const_align_of)]
 
 extern crate egl;
 extern crate errno;
@@ -13,8 +15,18 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+mod layout;
+
+pub mod error;
 pub mod gonk_gfx;
 pub mod gralloc;
 pub mod hardware;
 pub mod hwc;
+pub mod screen;
+#[cfg(feature = "stub-backend")]
+pub mod stub;
+pub mod sync;
+pub mod vsync;
+#[cfg(not(feature = "stub-backend"))]
 pub mod window;
@@ -15,9 +15,20 @@ struct GonkNativeWindow
     int (*set_dimensions)(struct ANativeWindow *window, int w, int h);
     int (*api_connect)(struct ANativeWindow *window, int api);
     int (*api_disconnect)(struct ANativeWindow *window, int api);
+    int (*set_buffer_count)(struct ANativeWindow *window, int count);
+    int (*set_scaling_mode)(struct ANativeWindow *window, int mode);
+    int (*set_surface_damage)(struct ANativeWindow *window,
+                              const android_native_rect_t *rects, size_t count);
 };
 
-// Rust doesn't support implementing variadic functions, so handle that here
+// Older window.h headers predate it.
+#ifndef NATIVE_WINDOW_SET_SURFACE_DAMAGE
+#define NATIVE_WINDOW_SET_SURFACE_DAMAGE 34
+#endif
+
+// Rust doesn't support implementing variadic functions, so handle that here.
+// The operations below are forwarded to the Rust side of GonkNativeWindow;
+// any other operation (crop, timestamps...) is rejected.
 
 extern "C" int
 gnw_perform(struct ANativeWindow *window, int op, ...)
@@ -60,6 +71,32 @@ gnw_perform(struct ANativeWindow *window, int op, ...)
         va_end(ap);
         return gnw->set_dimensions(window, w, h);
     }
+    case NATIVE_WINDOW_SET_BUFFER_COUNT:
+    {
+        int count;
+        va_start(ap, op);
+        count = va_arg(ap, int);
+        va_end(ap);
+        return gnw->set_buffer_count(window, count);
+    }
+    case NATIVE_WINDOW_SET_SCALING_MODE:
+    {
+        int mode;
+        va_start(ap, op);
+        mode = va_arg(ap, int);
+        va_end(ap);
+        return gnw->set_scaling_mode(window, mode);
+    }
+    case NATIVE_WINDOW_SET_SURFACE_DAMAGE:
+    {
+        const android_native_rect_t *rects;
+        size_t count;
+        va_start(ap, op);
+        rects = va_arg(ap, const android_native_rect_t *);
+        count = va_arg(ap, size_t);
+        va_end(ap);
+        return gnw->set_surface_damage(window, rects, count);
+    }
     case NATIVE_WINDOW_API_CONNECT:
     {
         int api;
@@ -0,0 +1,238 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Display power: idle timeouts, and restoring autosuspend when killed.
+
+use error::GonkGfxError;
+use gonk_gfx::{autosuspend_enable, set_autosuspend, AUTOSUSPEND_HOLDS};
+use hwc::{hwc_composer_device, HwcDevice, PowerMode};
+use libc::{c_int, raise, sigaction, sigemptyset, sighandler_t, signal, SIGINT, SIGTERM, SIG_DFL};
+use std::mem::zeroed;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+const LOG_TARGET: &'static str = "gonk-gfx::screen";
+
+/// Moves a display from Normal to Doze after `doze_timeout` without user
+/// activity, then to Off after `off_timeout`. Autosuspend is kept disabled
+/// until the display is off, and disabled again when activity wakes it up.
+///
+/// The embedder either calls `user_activity` and `update` itself, or reads
+/// its input events through `recv`, which does both.
+pub struct ScreenController {
+    display: c_int,
+    doze_timeout: Duration,
+    off_timeout: Duration,
+    last_activity: Instant,
+    mode: PowerMode,
+    // Whether we hold a set_autosuspend(false).
+    autosuspend_disabled: bool,
+}
+
+impl ScreenController {
+    /// Starts with `display` on. `off_timeout` is counted from the last
+    /// activity too; if it isn't longer than `doze_timeout` the display goes
+    /// straight to Off.
+    pub fn new(
+        hwc: &HwcDevice,
+        display: c_int,
+        doze_timeout: Duration,
+        off_timeout: Duration,
+    ) -> Result<ScreenController, GonkGfxError> {
+        hwc.set_power_mode(display, PowerMode::Normal)?;
+        set_autosuspend(false);
+        Ok(ScreenController {
+            display: display,
+            doze_timeout: doze_timeout,
+            off_timeout: off_timeout,
+            last_activity: Instant::now(),
+            mode: PowerMode::Normal,
+            autosuspend_disabled: true,
+        })
+    }
+
+    pub fn mode(&self) -> PowerMode {
+        self.mode
+    }
+
+    /// Restarts the timeouts, turning the display back on if needed.
+    pub fn user_activity(&mut self, hwc: &HwcDevice) -> Result<(), GonkGfxError> {
+        self.last_activity = Instant::now();
+        self.set_mode(hwc, PowerMode::Normal)
+    }
+
+    /// Applies the transition due by now, if any. Returns how long until the
+    /// next one, or None when the display is already off.
+    pub fn update(&mut self, hwc: &HwcDevice) -> Result<Option<Duration>, GonkGfxError> {
+        let idle = self.last_activity.elapsed();
+        if idle >= self.off_timeout {
+            self.set_mode(hwc, PowerMode::Off)?;
+            return Ok(None);
+        }
+        if idle >= self.doze_timeout && self.mode == PowerMode::Normal {
+            match self.set_mode(hwc, PowerMode::Doze) {
+                // No doze before HWC 1.4, stay on until Off.
+                Err(GonkGfxError::Unsupported) => (),
+                result => result?,
+            }
+        }
+        let next = if idle < self.doze_timeout && self.doze_timeout < self.off_timeout {
+            self.doze_timeout
+        } else {
+            self.off_timeout
+        };
+        Ok(Some(next - idle))
+    }
+
+    /// Waits for the next event on `events`, driving the timeouts meanwhile.
+    /// Any event counts as user activity and is returned as is, so the touch
+    /// that wakes the screen up still reaches the application.
+    pub fn recv<T>(&mut self, hwc: &HwcDevice, events: &Receiver<T>) -> Result<T, RecvError> {
+        loop {
+            let event = match self.update(hwc) {
+                Ok(Some(timeout)) => match events.recv_timeout(timeout) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Err(RecvError),
+                },
+                Ok(None) => events.recv()?,
+                Err(err) => {
+                    // Try again on the next activity.
+                    error!(target: LOG_TARGET, "Failed to update the screen: {}", err);
+                    events.recv()?
+                }
+            };
+            if let Err(err) = self.user_activity(hwc) {
+                error!(target: LOG_TARGET, "Failed to wake the screen up: {}", err);
+            }
+            return Ok(event);
+        }
+    }
+
+    fn set_mode(&mut self, hwc: &HwcDevice, mode: PowerMode) -> Result<(), GonkGfxError> {
+        if mode == self.mode {
+            return Ok(());
+        }
+        info!(target: LOG_TARGET, "Display {}: {:?} -> {:?}", self.display, self.mode, mode);
+        // Keep the CPU up while the panel turns on.
+        if mode != PowerMode::Off && !self.autosuspend_disabled {
+            set_autosuspend(false);
+            self.autosuspend_disabled = true;
+        }
+        hwc.set_power_mode(self.display, mode)?;
+        self.mode = mode;
+        if mode == PowerMode::Off && self.autosuspend_disabled {
+            set_autosuspend(true);
+            self.autosuspend_disabled = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScreenController {
+    fn drop(&mut self) {
+        if self.autosuspend_disabled {
+            set_autosuspend(true);
+        }
+    }
+}
+
+// What the signal handler restores, set by PowerGuard. The HWC device is a
+// usize, 0 when no power mode should be restored.
+static GUARD_INSTALLED: AtomicBool = AtomicBool::new(false);
+static GUARD_HWC: AtomicUsize = AtomicUsize::new(0);
+static GUARD_DISPLAY: AtomicUsize = AtomicUsize::new(0);
+static GUARD_POWER_MODE: AtomicUsize = AtomicUsize::new(0);
+
+const GUARDED_SIGNALS: [c_int; 2] = [SIGTERM, SIGINT];
+
+/// Makes SIGTERM and SIGINT re-enable autosuspend, and optionally set a power
+/// mode, before the process dies, so a killed shell doesn't leave the device
+/// unable to sleep. The previous handlers come back when the guard is
+/// dropped. Only one guard can be installed at a time.
+pub struct PowerGuard {
+    previous: [sigaction; 2],
+}
+
+impl PowerGuard {
+    pub fn install() -> Result<PowerGuard, GonkGfxError> {
+        PowerGuard::install_inner(None)
+    }
+
+    /// Also sets `display` to `mode` on the way out. The HWC is called from
+    /// the signal handler, which most HALs cope with but none promises; and
+    /// `hwc` must outlive the guard.
+    pub fn install_with_power_mode(
+        hwc: &HwcDevice,
+        display: c_int,
+        mode: PowerMode,
+    ) -> Result<PowerGuard, GonkGfxError> {
+        let value = hwc.power_mode_value(mode)?;
+        PowerGuard::install_inner(Some((hwc.native(), display, value)))
+    }
+
+    fn install_inner(
+        power_mode: Option<(*mut hwc_composer_device, c_int, c_int)>,
+    ) -> Result<PowerGuard, GonkGfxError> {
+        if GUARD_INSTALLED.swap(true, Ordering::SeqCst) {
+            error!(target: LOG_TARGET, "A PowerGuard is already installed");
+            return Err(GonkGfxError::InvalidArgument);
+        }
+        if let Some((hwc, display, value)) = power_mode {
+            GUARD_DISPLAY.store(display as usize, Ordering::SeqCst);
+            GUARD_POWER_MODE.store(value as usize, Ordering::SeqCst);
+            GUARD_HWC.store(hwc as usize, Ordering::SeqCst);
+        }
+
+        let mut guard = PowerGuard {
+            previous: unsafe { zeroed() },
+        };
+        unsafe {
+            let mut action: sigaction = zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as sighandler_t;
+            sigemptyset(&mut action.sa_mask);
+            for (&signum, previous) in GUARDED_SIGNALS.iter().zip(guard.previous.iter_mut()) {
+                sigaction(signum, &action, previous);
+            }
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for PowerGuard {
+    fn drop(&mut self) {
+        unsafe {
+            for (&signum, previous) in GUARDED_SIGNALS.iter().zip(self.previous.iter()) {
+                sigaction(signum, previous, ptr::null_mut());
+            }
+        }
+        GUARD_HWC.store(0, Ordering::SeqCst);
+        GUARD_INSTALLED.store(false, Ordering::SeqCst);
+    }
+}
+
+// Only atomics, the HAL calls and signal()/raise() in here: no logging, no
+// locks, no allocations.
+extern "C" fn on_signal(signum: c_int) {
+    let hwc = GUARD_HWC.load(Ordering::SeqCst) as *mut hwc_composer_device;
+    if !hwc.is_null() {
+        let display = GUARD_DISPLAY.load(Ordering::SeqCst) as c_int;
+        let value = GUARD_POWER_MODE.load(Ordering::SeqCst) as c_int;
+        unsafe {
+            ((*hwc).set_power_mode)(hwc, display, value);
+        }
+    }
+    if AUTOSUSPEND_HOLDS.load(Ordering::SeqCst) > 0 {
+        unsafe {
+            autosuspend_enable();
+        }
+    }
+    // Die from the signal as we would have without the guard.
+    unsafe {
+        signal(signum, SIG_DFL);
+        raise(signum);
+    }
+}
@@ -0,0 +1,735 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A fake gralloc and HWC, built with the `stub-backend` feature to run the
+//! window's dequeue/queue/draw flow on a desktop, without libhardware.
+//!
+//! Buffers live on the heap and are always 4 bytes per pixel. The fake HWC
+//! copies the framebuffer target of each frame into its own framebuffer,
+//! which can be saved with `save_ppm`.
+
+use gonk_gfx::*;
+use gralloc::*;
+use hardware::*;
+use hwc::*;
+use libc::{c_char, c_int, c_void, clock_gettime, size_t, timespec, CLOCK_MONOTONIC};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use sync::Fence;
+
+const LOG_TARGET: &'static str = "gonk-gfx::stub";
+
+pub const STUB_DISPLAY_WIDTH: i32 = 480;
+pub const STUB_DISPLAY_HEIGHT: i32 = 800;
+pub const STUB_DISPLAY_DPI: i32 = 160;
+pub const STUB_VSYNC_PERIOD_NS: i32 = 16_666_667;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+// A gralloc buffer: a native_handle with no fds and three ints, followed by
+// the pixels.
+#[repr(C)]
+struct StubBuffer {
+    handle: native_handle,
+    ints: [c_int; 3], // width, height, stride
+    pixels: Vec<u8>,
+}
+
+#[repr(C)]
+struct StubHwc {
+    device: hwc_composer_device,
+    framebuffer: Vec<u8>,
+    // The registered hwc_procs, as a usize to share it with the vsync thread.
+    procs: Arc<AtomicUsize>,
+    vsync_enabled: Arc<AtomicBool>,
+    vsync_thread: Mutex<Option<JoinHandle<()>>>,
+    prepares: AtomicUsize,
+    sets: AtomicUsize,
+}
+
+// What the stub gralloc handed out and took back, for the tests to check
+// that nothing leaks.
+static BUFFERS_ALLOCATED: AtomicUsize = ATOMIC_USIZE_INIT;
+static BUFFERS_FREED: AtomicUsize = ATOMIC_USIZE_INIT;
+static DEVICES_OPENED: AtomicUsize = ATOMIC_USIZE_INIT;
+static DEVICES_CLOSED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static INIT: Once = ONCE_INIT;
+static mut GRALLOC_MODULE: *const gralloc_module = 0 as *const gralloc_module;
+static mut HWC_MODULE: *const hw_module = 0 as *const hw_module;
+
+fn module(id: &'static [u8], methods: *mut hw_module_methods) -> hw_module {
+    hw_module {
+        tag: hardware_tag(),
+        module_api_version: 1,
+        hal_api_version: 0,
+        id: id.as_ptr() as *const c_char,
+        name: id.as_ptr() as *const c_char,
+        author: b"gonk-gfx\0".as_ptr() as *const c_char,
+        methods,
+        dso: ptr::null_mut(),
+        reserved: [0; (32 - 7)],
+    }
+}
+
+fn hardware_tag() -> u32 {
+    (b'H' as u32) << 24 | (b'W' as u32) << 16 | (b'M' as u32) << 8 | b'T' as u32
+}
+
+fn init_modules() {
+    INIT.call_once(|| unsafe {
+        let gralloc_methods = Box::into_raw(Box::new(hw_module_methods { open: gralloc_open }));
+        GRALLOC_MODULE = Box::into_raw(Box::new(gralloc_module {
+            common: module(b"gralloc\0", gralloc_methods),
+            register_buffer: register_buffer,
+            unregister_buffer: register_buffer,
+            lock: lock,
+            unlock: register_buffer,
+            perform: gnw_stub_gralloc_perform,
+            lock_ycbcr: lock_ycbcr,
+            reserved: [ptr::null_mut(); 6],
+        }));
+        let hwc_methods = Box::into_raw(Box::new(hw_module_methods { open: hwc_open }));
+        HWC_MODULE = Box::into_raw(Box::new(module(b"hwcomposer\0", hwc_methods)));
+    });
+}
+
+/// Stands in for libhardware's `hw_get_module`.
+pub unsafe fn hw_get_module(id: *const c_char, module: *mut *const hw_module) -> c_int {
+    init_modules();
+    match CStr::from_ptr(id).to_bytes() {
+        b"gralloc" => {
+            *module = GRALLOC_MODULE as *const hw_module;
+            0
+        }
+        b"hwcomposer" => {
+            *module = HWC_MODULE;
+            0
+        }
+        _ => -::libc::ENOENT,
+    }
+}
+
+/// Stands in for libsuspend's `autosuspend_enable`.
+pub unsafe fn autosuspend_enable() -> c_int {
+    0
+}
+
+/// Stands in for libsuspend's `autosuspend_disable`.
+pub unsafe fn autosuspend_disable() -> c_int {
+    0
+}
+
+/// Stands in for libcutils' `property_get`. There are no properties, so
+/// every key gets its default.
+pub unsafe fn property_get(
+    _key: *const c_char,
+    value: *mut c_char,
+    default_value: *const c_char,
+) -> c_int {
+    if default_value.is_null() {
+        *value = 0;
+        return 0;
+    }
+    let default = CStr::from_ptr(default_value).to_bytes_with_nul();
+    ptr::copy_nonoverlapping(default.as_ptr() as *const c_char, value, default.len());
+    default.len() as c_int - 1
+}
+
+// Called by the variadic `gnw_perform` of src/stub_glue.cpp, which Rust
+// can't implement.
+#[no_mangle]
+pub extern "C" fn gnw_stub_perform(op: c_int) -> c_int {
+    warn!(target: LOG_TARGET, "Unsupported perform operation {}", op);
+    -1
+}
+
+fn device_header(module: *const hw_module, version: u32) -> hw_device {
+    hw_device {
+        tag: (b'H' as u32) << 24 | (b'W' as u32) << 16 | (b'D' as u32) << 8 | b'T' as u32,
+        version,
+        module: module as *mut hw_module,
+        reserved: [0; 12],
+        close: close_device,
+    }
+}
+
+extern "C" fn close_device(device: *mut hw_device) -> c_int {
+    debug!(target: LOG_TARGET, "Closing device {:?}", device);
+    0
+}
+
+// gralloc
+
+extern "C" fn gralloc_open(
+    module: *const hw_module,
+    _name: *const c_char,
+    device: *mut *const hw_device,
+) -> c_int {
+    let mut common = device_header(module, 0);
+    common.close = gralloc_close;
+    let alloc_dev = Box::new(alloc_device {
+        common: common,
+        alloc: alloc,
+        free: free,
+        dump: None,
+        reserved: [ptr::null_mut(); 7],
+    });
+    unsafe {
+        *device = Box::into_raw(alloc_dev) as *const hw_device;
+    }
+    DEVICES_OPENED.fetch_add(1, Ordering::SeqCst);
+    0
+}
+
+extern "C" fn gralloc_close(device: *mut hw_device) -> c_int {
+    let ret = close_device(device);
+    drop(unsafe { Box::from_raw(device as *mut alloc_device) });
+    DEVICES_CLOSED.fetch_add(1, Ordering::SeqCst);
+    ret
+}
+
+extern "C" fn alloc(
+    _dev: *mut alloc_device,
+    width: c_int,
+    height: c_int,
+    _format: c_int,
+    _usage: c_int,
+    handle: *mut *const native_handle,
+    stride: *mut c_int,
+) -> c_int {
+    if width <= 0 || height <= 0 {
+        return -::libc::EINVAL;
+    }
+    let buffer = Box::new(StubBuffer {
+        handle: native_handle::new(0, 3),
+        ints: [width, height, width],
+        pixels: vec![0; width as usize * height as usize * BYTES_PER_PIXEL],
+    });
+    unsafe {
+        *stride = width;
+        *handle = Box::into_raw(buffer) as *const native_handle;
+    }
+    BUFFERS_ALLOCATED.fetch_add(1, Ordering::SeqCst);
+    0
+}
+
+extern "C" fn free(_dev: *mut alloc_device, handle: *const native_handle) -> c_int {
+    drop(unsafe { Box::from_raw(handle as *mut StubBuffer) });
+    BUFFERS_FREED.fetch_add(1, Ordering::SeqCst);
+    0
+}
+
+extern "C" fn register_buffer(_module: *const gralloc_module, _: *const native_handle) -> c_int {
+    0
+}
+
+extern "C" fn lock(
+    _module: *const gralloc_module,
+    handle: *const native_handle,
+    _usage: c_int,
+    _l: c_int,
+    _t: c_int,
+    _w: c_int,
+    _h: c_int,
+    vaddr: *mut *mut c_void,
+) -> c_int {
+    unsafe {
+        *vaddr = (*(handle as *mut StubBuffer)).pixels.as_mut_ptr() as *mut c_void;
+    }
+    0
+}
+
+extern "C" fn lock_ycbcr(
+    _module: *const gralloc_module,
+    _handle: *const native_handle,
+    _usage: c_int,
+    _l: c_int,
+    _t: c_int,
+    _w: c_int,
+    _h: c_int,
+    _ycbcr: *mut android_ycbcr,
+) -> c_int {
+    -::libc::EINVAL
+}
+
+// In src/stub_glue.cpp, as Rust can't implement a variadic function.
+extern "C" {
+    fn gnw_stub_gralloc_perform(module: *const gralloc_module, op: c_int, ...) -> c_int;
+}
+
+/// Fills a buffer with a single RGBA color, as a producer would render.
+pub fn fill_buffer(buf: *mut ANativeWindowBuffer, color: [u8; 4]) {
+    let buffer = unsafe { &mut *((*buf).handle() as *mut StubBuffer) };
+    for pixel in buffer.pixels.chunks_mut(BYTES_PER_PIXEL) {
+        pixel.copy_from_slice(&color);
+    }
+}
+
+// hwcomposer
+
+extern "C" fn hwc_open(
+    module: *const hw_module,
+    _name: *const c_char,
+    device: *mut *const hw_device,
+) -> c_int {
+    let mut common = device_header(module, HwcApiVersion::hwc_api_version(1, 4));
+    common.close = hwc_close;
+    let hwc = Box::new(StubHwc {
+        device: hwc_composer_device {
+            common: common,
+            prepare: prepare,
+            set: set,
+            event_control: event_control,
+            set_power_mode: set_power_mode,
+            query: query,
+            register_procs: register_procs,
+            dump: dump,
+            get_display_configs: get_display_configs,
+            get_display_attributes: get_display_attributes,
+            get_active_config: Some(get_active_config),
+            set_active_config: Some(set_active_config),
+            set_cursor_position_async: None,
+            reserved: [ptr::null_mut(); 1],
+        },
+        framebuffer: vec![
+            0;
+            STUB_DISPLAY_WIDTH as usize
+                * STUB_DISPLAY_HEIGHT as usize
+                * BYTES_PER_PIXEL
+        ],
+        procs: Arc::new(AtomicUsize::new(0)),
+        vsync_enabled: Arc::new(AtomicBool::new(false)),
+        vsync_thread: Mutex::new(None),
+        prepares: AtomicUsize::new(0),
+        sets: AtomicUsize::new(0),
+    });
+    unsafe {
+        *device = Box::into_raw(hwc) as *const hw_device;
+    }
+    0
+}
+
+fn layers<'a>(contents: *mut hwc_display_contents) -> &'a mut [hwc_layer] {
+    unsafe {
+        slice::from_raw_parts_mut(
+            (*contents).hw_layers.as_mut_ptr(),
+            (*contents).num_hw_layers as usize,
+        )
+    }
+}
+
+extern "C" fn prepare(
+    dev: *mut hwc_composer_device,
+    num_displays: size_t,
+    displays: *mut *mut hwc_display_contents,
+) -> c_int {
+    stub_hwc(dev).prepares.fetch_add(1, Ordering::SeqCst);
+    let displays = unsafe { slice::from_raw_parts(displays, num_displays as usize) };
+    for &contents in displays.iter().filter(|contents| !contents.is_null()) {
+        // Everything gets composited with GL into the framebuffer target.
+        for layer in layers(contents) {
+            if layer.composition_type != HWC_FRAMEBUFFER_TARGET {
+                layer.composition_type = HWC_FRAMEBUFFER;
+            }
+        }
+    }
+    0
+}
+
+extern "C" fn set(
+    dev: *mut hwc_composer_device,
+    num_displays: size_t,
+    displays: *mut *mut hwc_display_contents,
+) -> c_int {
+    let hwc = unsafe { &mut *(dev as *mut StubHwc) };
+    hwc.sets.fetch_add(1, Ordering::SeqCst);
+    let displays = unsafe { slice::from_raw_parts(displays, num_displays as usize) };
+    if displays.is_empty() || displays[0].is_null() {
+        return 0;
+    }
+    unsafe {
+        (*displays[0]).retire_fence_fd = -1;
+    }
+    for layer in layers(displays[0]) {
+        // Like a real HWC, we own the acquire fences. The copy below is done
+        // before we return, so a buffer is free again as soon as its
+        // producer is done with it: its release fence is its acquire fence.
+        let acquire = Fence::from_raw(layer.acquire_fence_fd);
+        layer.release_fence_fd = acquire.try_clone().map_or(-1, Fence::into_raw);
+        if layer.composition_type != HWC_FRAMEBUFFER_TARGET || layer.handle.is_null() {
+            continue;
+        }
+        let buffer = unsafe { &*(layer.handle as *const StubBuffer) };
+        let width = buffer.ints[0].min(STUB_DISPLAY_WIDTH) as usize;
+        let height = buffer.ints[1].min(STUB_DISPLAY_HEIGHT) as usize;
+        let src_stride = buffer.ints[2] as usize * BYTES_PER_PIXEL;
+        let dst_stride = STUB_DISPLAY_WIDTH as usize * BYTES_PER_PIXEL;
+        for row in 0..height {
+            let src = &buffer.pixels[row * src_stride..row * src_stride + width * BYTES_PER_PIXEL];
+            hwc.framebuffer[row * dst_stride..row * dst_stride + width * BYTES_PER_PIXEL]
+                .copy_from_slice(src);
+        }
+    }
+    0
+}
+
+fn stub_hwc<'a>(dev: *mut hwc_composer_device) -> &'a StubHwc {
+    unsafe { &*(dev as *const StubHwc) }
+}
+
+extern "C" fn hwc_close(device: *mut hw_device) -> c_int {
+    set_vsync(stub_hwc(device as *mut hwc_composer_device), false);
+    close_device(device)
+}
+
+fn monotonic_ns() -> i64 {
+    let mut now = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        clock_gettime(CLOCK_MONOTONIC, &mut now);
+    }
+    now.tv_sec as i64 * 1_000_000_000 + now.tv_nsec as i64
+}
+
+// Fakes vsync with a thread ticking every STUB_VSYNC_PERIOD_NS.
+fn set_vsync(hwc: &StubHwc, enabled: bool) {
+    let mut thread = hwc.vsync_thread.lock().unwrap();
+    hwc.vsync_enabled.store(enabled, Ordering::SeqCst);
+    if !enabled {
+        if let Some(thread) = thread.take() {
+            let _ = thread.join();
+        }
+        return;
+    }
+    if thread.is_some() {
+        return;
+    }
+    let procs = hwc.procs.clone();
+    let vsync_enabled = hwc.vsync_enabled.clone();
+    *thread = Some(thread::spawn(move || loop {
+        thread::sleep(Duration::new(0, STUB_VSYNC_PERIOD_NS as u32));
+        if !vsync_enabled.load(Ordering::SeqCst) {
+            break;
+        }
+        let procs = procs.load(Ordering::SeqCst) as *const hwc_procs;
+        if !procs.is_null() {
+            unsafe {
+                ((*procs).vsync)(procs, HWC_DISPLAY_PRIMARY, monotonic_ns());
+            }
+        }
+    }));
+}
+
+extern "C" fn event_control(
+    dev: *mut hwc_composer_device,
+    display: c_int,
+    event: c_int,
+    enabled: c_int,
+) -> c_int {
+    if display != HWC_DISPLAY_PRIMARY || event != HWC_EVENT_VSYNC {
+        return -::libc::EINVAL;
+    }
+    set_vsync(stub_hwc(dev), enabled != 0);
+    0
+}
+
+extern "C" fn set_power_mode(_: *mut hwc_composer_device, display: c_int, mode: c_int) -> c_int {
+    info!(target: LOG_TARGET, "Display {} power mode {}", display, mode);
+    0
+}
+
+extern "C" fn query(_: *mut hwc_composer_device, _: c_int, _: *mut c_int) -> c_int {
+    -::libc::EINVAL
+}
+
+extern "C" fn register_procs(dev: *mut hwc_composer_device, procs: *const hwc_procs) {
+    stub_hwc(dev).procs.store(procs as usize, Ordering::SeqCst);
+}
+
+extern "C" fn dump(_: *mut hwc_composer_device, buffer: *mut c_char, len: c_int) {
+    let text = b"stub hwcomposer\n\0";
+    let len = (len as usize).min(text.len());
+    unsafe {
+        ptr::copy_nonoverlapping(text.as_ptr() as *const c_char, buffer, len);
+    }
+}
+
+extern "C" fn get_display_configs(
+    _: *mut hwc_composer_device,
+    display: c_int,
+    configs: *mut u32,
+    num_configs: *mut size_t,
+) -> c_int {
+    if display != HWC_DISPLAY_PRIMARY {
+        return -::libc::EINVAL;
+    }
+    unsafe {
+        if *num_configs > 0 {
+            *configs = 0;
+        }
+        *num_configs = 1;
+    }
+    0
+}
+
+extern "C" fn get_display_attributes(
+    _: *mut hwc_composer_device,
+    display: c_int,
+    _config: u32,
+    attributes: *const u32,
+    values: *mut i32,
+) -> c_int {
+    if display != HWC_DISPLAY_PRIMARY {
+        return -::libc::EINVAL;
+    }
+    let mut idx = 0;
+    unsafe {
+        loop {
+            let value = match *attributes.offset(idx) {
+                HWC_DISPLAY_NO_ATTRIBUTE => break,
+                HWC_DISPLAY_VSYNC_PERIOD => STUB_VSYNC_PERIOD_NS,
+                HWC_DISPLAY_WIDTH => STUB_DISPLAY_WIDTH,
+                HWC_DISPLAY_HEIGHT => STUB_DISPLAY_HEIGHT,
+                HWC_DISPLAY_DPI_X | HWC_DISPLAY_DPI_Y => STUB_DISPLAY_DPI * 1000,
+                _ => 0,
+            };
+            *values.offset(idx) = value;
+            idx += 1;
+        }
+    }
+    0
+}
+
+// The stub display only has config 0.
+extern "C" fn get_active_config(_: *mut hwc_composer_device, display: c_int) -> c_int {
+    if display != HWC_DISPLAY_PRIMARY {
+        return -::libc::EINVAL;
+    }
+    0
+}
+
+extern "C" fn set_active_config(
+    _: *mut hwc_composer_device,
+    display: c_int,
+    index: c_int,
+) -> c_int {
+    if display != HWC_DISPLAY_PRIMARY || index != 0 {
+        return -::libc::EINVAL;
+    }
+    0
+}
+
+/// Saves the last frame presented by a stub HWC device as a binary PPM.
+pub fn save_ppm(hwc: &HwcDevice, path: &Path) -> io::Result<()> {
+    let hwc = unsafe { &*(hwc.native() as *const StubHwc) };
+    let mut file = File::create(path)?;
+    write!(
+        file,
+        "P6\n{} {}\n255\n",
+        STUB_DISPLAY_WIDTH, STUB_DISPLAY_HEIGHT
+    )?;
+    for pixel in hwc.framebuffer.chunks(BYTES_PER_PIXEL) {
+        file.write_all(&pixel[..3])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errno::errno;
+    use error::GonkGfxError;
+    use libc::{pipe, write, EPIPE};
+    use std::mem::replace;
+    use std::sync::mpsc::channel;
+    use std::sync::MutexGuard;
+    use std::time::Instant;
+
+    // The gralloc counters are shared: one test at a time.
+    static LOCK_INIT: Once = ONCE_INIT;
+    static mut LOCK: *const Mutex<()> = 0 as *const Mutex<()>;
+
+    fn serialize() -> MutexGuard<'static, ()> {
+        unsafe {
+            LOCK_INIT.call_once(|| LOCK = Box::into_raw(Box::new(Mutex::new(()))));
+            (*LOCK).lock().unwrap_or_else(|err| err.into_inner())
+        }
+    }
+
+    // Buffers allocated and not freed, and gralloc devices left open.
+    fn leaks() -> (usize, usize) {
+        (
+            BUFFERS_ALLOCATED.load(Ordering::SeqCst) - BUFFERS_FREED.load(Ordering::SeqCst),
+            DEVICES_OPENED.load(Ordering::SeqCst) - DEVICES_CLOSED.load(Ordering::SeqCst),
+        )
+    }
+
+    fn connected_window(hwc: &HwcDevice) -> *mut GonkNativeWindow {
+        let usage = GRALLOC_USAGE_HW_FB | GRALLOC_USAGE_HW_RENDER | GRALLOC_USAGE_HW_COMPOSER;
+        let window = GonkNativeWindow::new(hwc.native(), 64, 32, usage).unwrap();
+        unsafe {
+            (*window).connect(NATIVE_WINDOW_API_CPU).unwrap();
+        }
+        window
+    }
+
+    fn release(window: *mut GonkNativeWindow) {
+        unsafe {
+            ((*window).window.common.dec_ref)(&mut (*window).window.common);
+        }
+    }
+
+    // A fence which is the read end of a pipe, and the write end, which
+    // can't be written to anymore once every copy of the fence is closed.
+    fn pipe_fence() -> (Fence, c_int) {
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { pipe(fds.as_mut_ptr()) }, 0);
+        (Fence::from_raw(fds[0]), fds[1])
+    }
+
+    fn is_closed(write_end: c_int) -> bool {
+        let ret = unsafe { write(write_end, b"x".as_ptr() as *const c_void, 1) };
+        ret < 0 && errno().0 == EPIPE
+    }
+
+    #[test]
+    fn dequeue_queue_round_trip() {
+        let _lock = serialize();
+        let hwc = HwcDevice::new().unwrap();
+        let window = connected_window(&hwc);
+        let colors = [[0xff, 0, 0, 0xff], [0, 0xff, 0, 0xff], [0, 0, 0xff, 0xff]];
+        unsafe {
+            for color in &colors {
+                let (buf, _fence) = (*window).dequeue().unwrap();
+                let buffer = &*((*buf).handle() as *const StubBuffer);
+                assert_eq!(buffer.ints[..2], [64, 32]);
+                fill_buffer(buf, *color);
+                (*window).queue(buf, Fence::none()).unwrap();
+            }
+        }
+        let stub = unsafe { &*(hwc.native() as *const StubHwc) };
+        assert_eq!(stub.prepares.load(Ordering::SeqCst), colors.len());
+        assert_eq!(stub.sets.load(Ordering::SeqCst), colors.len());
+        assert_eq!(&stub.framebuffer[..BYTES_PER_PIXEL], &colors[2][..]);
+        release(window);
+    }
+
+    #[test]
+    fn drop_frees_everything() {
+        let _lock = serialize();
+        let before = leaks();
+        let hwc = HwcDevice::new().unwrap();
+        let window = connected_window(&hwc);
+        let held = unsafe {
+            let (buf, _fence) = (*window).dequeue().unwrap();
+            (*window).queue(buf, Fence::none()).unwrap();
+            (*window).dequeue().unwrap().0
+        };
+        release(window);
+        // The buffer the producer still holds keeps the device open.
+        assert_eq!(leaks(), (before.0 + 1, before.1 + 1));
+        // The producer's reference, through the buffer's ANativeBase header.
+        let held = held as *mut ANativeBase;
+        unsafe {
+            ((*held).dec_ref)(held);
+        }
+        assert_eq!(leaks(), before);
+    }
+
+    #[test]
+    fn builder_rejects_bad_input() {
+        let _lock = serialize();
+        let hwc = HwcDevice::new().unwrap();
+        let builder = || GonkNativeWindowBuilder::new().dimensions(64, 32);
+        assert_eq!(builder().build().err(), Some(GonkGfxError::InvalidArgument));
+        let one_buffer = builder().hwc_device(hwc.native()).buffer_count(1).build();
+        assert_eq!(one_buffer.err(), Some(GonkGfxError::InvalidArgument));
+        let hwc2 = unsafe {
+            let version = &mut (*hwc.native()).common.version;
+            let hwc1 = replace(version, HwcApiVersion::hwc_api_version(2, 0));
+            let result = builder().hwc_device(hwc.native()).build();
+            *version = hwc1;
+            result
+        };
+        assert_eq!(hwc2.err(), Some(GonkGfxError::Unsupported));
+    }
+
+    #[test]
+    fn dequeue_while_presenting() {
+        let _lock = serialize();
+        let hwc = HwcDevice::new().unwrap();
+        let window = connected_window(&hwc);
+        // Vsync events that never come: presenting waits for them in vain.
+        let (_vsync, events) = channel();
+        let buf = unsafe {
+            (*window).set_vsync_events(Some(events));
+            (*window).dequeue().unwrap().0
+        };
+        let (queued_window, queued_buf) = (window as usize, buf as usize);
+        let queue = thread::spawn(move || unsafe {
+            let window = queued_window as *mut GonkNativeWindow;
+            (*window).queue(queued_buf as *mut ANativeWindowBuffer, Fence::none())
+        });
+        thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        let buf = unsafe { (*window).dequeue().unwrap().0 };
+        assert!(start.elapsed() < Duration::from_millis(50));
+        queue.join().unwrap().unwrap();
+        unsafe {
+            (*window).cancel(buf, Fence::none()).unwrap();
+        }
+        release(window);
+    }
+
+    #[test]
+    fn set_display_holds_autosuspend_once() {
+        let _lock = serialize();
+        let holds = || ::gonk_gfx::AUTOSUSPEND_HOLDS.load(Ordering::SeqCst);
+        let before = holds();
+        let hwc = HwcDevice::new().unwrap();
+        hwc.set_display(true);
+        hwc.set_display(true);
+        assert_eq!(holds(), before + 1);
+        hwc.set_display(false);
+        hwc.set_display(false);
+        assert_eq!(holds(), before);
+        // Closing the device lets go of its hold too.
+        hwc.set_display(true);
+        drop(hwc);
+        assert_eq!(holds(), before);
+    }
+
+    #[test]
+    fn fences_are_closed() {
+        let _lock = serialize();
+        let hwc = HwcDevice::new().unwrap();
+        let window = connected_window(&hwc);
+        let (fence, write_end) = pipe_fence();
+        unsafe {
+            let (buf, _fence) = (*window).dequeue().unwrap();
+            (*window).queue(buf, fence).unwrap();
+            // The HWC closed the acquire fence, and handed it back as the
+            // release fence of the buffer, which the window keeps.
+            assert!(!is_closed(write_end));
+            let (buf, _fence) = (*window).dequeue().unwrap();
+            (*window).queue(buf, Fence::none()).unwrap();
+        }
+        release(window);
+        assert!(is_closed(write_end));
+        unsafe {
+            ::libc::close(write_end);
+        }
+    }
+}
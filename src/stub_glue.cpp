@@ -0,0 +1,27 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Stands in for native_window_glue.cpp with the stub backend, which has no
+// Gonk headers to build it against, along with the variadic functions of the
+// stub HALs. Nothing calls perform without an EGL driver, so every operation
+// is rejected, after stub.rs logs it.
+
+#include <errno.h>
+
+struct ANativeWindow;
+struct gralloc_module_t;
+
+extern "C" int gnw_stub_perform(int op);
+
+extern "C" int
+gnw_perform(struct ANativeWindow *window, int op, ...)
+{
+    return gnw_stub_perform(op);
+}
+
+extern "C" int
+gnw_stub_gralloc_perform(const struct gralloc_module_t *module, int op, ...)
+{
+    return -EINVAL;
+}
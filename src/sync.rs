@@ -0,0 +1,297 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Sync fence helpers, like system/core/libsync.
+
+use errno::errno;
+use libc::{c_char, c_int, close, dup, ioctl, poll, pollfd, ENOTTY, POLLIN};
+use std::mem::forget;
+
+const LOG_TARGET: &'static str = "gonk-gfx::sync";
+
+// From include/uapi/linux/sync_file.h, since Linux 4.7.
+#[repr(C)]
+struct sync_merge_data {
+    name: [c_char; 32],
+    fd2: c_int,
+    fence: c_int,
+    flags: u32,
+    pad: u32,
+}
+
+const SYNC_IOC_MERGE: u32 = 0xc030_3e03;
+// The ioctl numbers above and below encode these sizes.
+assert_size!(SYNC_MERGE_DATA, sync_merge_data, 0x30);
+assert_align!(SYNC_MERGE_DATA_ALIGN, sync_merge_data, 4);
+
+// From the older drivers/staging/android/uapi/sync.h our kernels have.
+#[repr(C)]
+struct sync_legacy_merge_data {
+    fd2: c_int,
+    name: [c_char; 32],
+    fence: c_int,
+}
+
+const SYNC_IOC_LEGACY_MERGE: u32 = 0xc028_3e01;
+assert_size!(SYNC_LEGACY_MERGE_DATA, sync_legacy_merge_data, 0x28);
+assert_align!(SYNC_LEGACY_MERGE_DATA_ALIGN, sync_legacy_merge_data, 4);
+
+/// An owned sync fence fd, closed when dropped. A fence of -1 stands for
+/// none, which is as good as signaled.
+#[derive(Debug)]
+pub struct Fence(c_int);
+
+impl Fence {
+    pub fn none() -> Fence {
+        Fence(-1)
+    }
+
+    /// Takes ownership of `fd`, e.g. one handed in through the
+    /// ANativeWindow callbacks.
+    pub fn from_raw(fd: c_int) -> Fence {
+        Fence(if fd < 0 { -1 } else { fd })
+    }
+
+    /// Gives up ownership of the fd, e.g. to hand it out through the
+    /// ANativeWindow callbacks. -1 if there is none.
+    pub fn into_raw(self) -> c_int {
+        let fd = self.0;
+        forget(self);
+        fd
+    }
+
+    /// The fd, still owned by the fence.
+    pub fn as_raw(&self) -> c_int {
+        self.0
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.0 < 0
+    }
+
+    /// Whether the fence signaled already, without waiting for it. No fence
+    /// counts as signaled.
+    pub fn is_signaled(&self) -> bool {
+        if self.is_none() {
+            return true;
+        }
+        let mut pfd = pollfd {
+            fd: self.0,
+            events: POLLIN,
+            revents: 0,
+        };
+        unsafe { poll(&mut pfd, 1, 0) > 0 }
+    }
+
+    /// Another fd for the same fence, e.g. to hand it out twice. None on
+    /// failure.
+    pub fn try_clone(&self) -> Option<Fence> {
+        if self.is_none() {
+            return Some(Fence::none());
+        }
+        let fd = unsafe { dup(self.0) };
+        if fd < 0 {
+            error!(target: LOG_TARGET, "Failed to dup fence: {}", errno());
+            None
+        } else {
+            Some(Fence(fd))
+        }
+    }
+
+    /// See `merge_fences`. None on failure.
+    pub fn merge(&self, other: &Fence) -> Option<Fence> {
+        if self.is_none() && other.is_none() {
+            return Some(Fence::none());
+        }
+        let fd = merge_fences(self.0, other.0);
+        if fd < 0 {
+            None
+        } else {
+            Some(Fence(fd))
+        }
+    }
+}
+
+impl Default for Fence {
+    fn default() -> Fence {
+        Fence::none()
+    }
+}
+
+impl Drop for Fence {
+    fn drop(&mut self) {
+        if self.0 >= 0 {
+            unsafe {
+                close(self.0);
+            }
+        }
+    }
+}
+
+fn fence_name(name: &[u8]) -> [c_char; 32] {
+    let mut result = [0; 32];
+    for (dst, &src) in result.iter_mut().zip(name) {
+        *dst = src as c_char;
+    }
+    result
+}
+
+/// Returns a new fence signaling once both `a` and `b` did, e.g. to hand a
+/// producer a single release fence for a buffer shown in several layers.
+/// If one of them is -1 this is a dup of the other, and -1 if both are.
+/// `a` and `b` stay owned by the caller. Returns -1 on failure.
+pub fn merge_fences(a: c_int, b: c_int) -> c_int {
+    if a < 0 && b < 0 {
+        return -1;
+    }
+    if a < 0 || b < 0 {
+        let fd = unsafe { dup(if a < 0 { b } else { a }) };
+        if fd < 0 {
+            error!(target: LOG_TARGET, "Failed to dup fence: {}", errno());
+        }
+        return fd;
+    }
+
+    let mut data = sync_merge_data {
+        name: fence_name(b"gonk-gfx merge"),
+        fd2: b,
+        fence: -1,
+        flags: 0,
+        pad: 0,
+    };
+    if unsafe { ioctl(a, SYNC_IOC_MERGE as _, &mut data) } == 0 {
+        return data.fence;
+    }
+    if errno().0 != ENOTTY {
+        error!(target: LOG_TARGET, "Failed to merge fences {} and {}: {}", a, b, errno());
+        return -1;
+    }
+
+    let mut data = sync_legacy_merge_data {
+        fd2: b,
+        name: fence_name(b"gonk-gfx merge"),
+        fence: -1,
+    };
+    if unsafe { ioctl(a, SYNC_IOC_LEGACY_MERGE as _, &mut data) } != 0 {
+        error!(target: LOG_TARGET, "Failed to merge fences {} and {}: {}", a, b, errno());
+        return -1;
+    }
+    data.fence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{open, pipe, write, EPIPE, O_RDWR};
+    use std::ffi::CString;
+
+    // From drivers/staging/android/uapi/sw_sync.h.
+    #[repr(C)]
+    struct sw_sync_create_fence_data {
+        value: u32,
+        name: [c_char; 32],
+        fence: c_int,
+    }
+
+    const SW_SYNC_IOC_CREATE_FENCE: u32 = 0xc028_5700;
+    assert_size!(SW_SYNC_CREATE_FENCE_DATA, sw_sync_create_fence_data, 0x28);
+    assert_align!(
+        SW_SYNC_CREATE_FENCE_DATA_ALIGN,
+        sw_sync_create_fence_data,
+        4
+    );
+
+    // Returns a fence that is already signaled. It comes from a sw_sync
+    // timeline, which needs CONFIG_SW_SYNC in the kernel. Returns -1 on
+    // failure.
+    fn create_signaled_fence() -> c_int {
+        let timeline = ["/dev/sw_sync", "/sys/kernel/debug/sync/sw_sync"]
+            .iter()
+            .map(|path| {
+                let path = CString::new(*path).unwrap();
+                unsafe { open(path.as_ptr(), O_RDWR) }
+            })
+            .find(|&fd| fd >= 0);
+        let timeline = match timeline {
+            Some(fd) => fd,
+            None => {
+                error!(target: LOG_TARGET, "No sw_sync timeline: {}", errno());
+                return -1;
+            }
+        };
+
+        // The timeline starts at 0, so a fence on value 0 is signaled
+        // already, and stays so once the timeline is closed.
+        let mut data = sw_sync_create_fence_data {
+            value: 0,
+            name: fence_name(b"gonk-gfx signaled"),
+            fence: -1,
+        };
+        let ret = unsafe { ioctl(timeline, SW_SYNC_IOC_CREATE_FENCE as _, &mut data) };
+        if ret != 0 {
+            error!(target: LOG_TARGET, "Failed to create a fence: {}", errno());
+        }
+        unsafe {
+            close(timeline);
+        }
+        if ret != 0 {
+            return -1;
+        }
+        data.fence
+    }
+
+    // A fence which is the read end of a pipe, and the write end, which
+    // can't be written to anymore once every copy of the fence is closed.
+    fn pipe_fence() -> (c_int, c_int) {
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { pipe(fds.as_mut_ptr()) }, 0);
+        (fds[0], fds[1])
+    }
+
+    fn is_closed(write_end: c_int) -> bool {
+        let ret = unsafe { write(write_end, b"x".as_ptr() as *const _, 1) };
+        ret < 0 && errno().0 == EPIPE
+    }
+
+    #[test]
+    fn merge_nothing() {
+        assert_eq!(merge_fences(-1, -1), -1);
+        assert!(Fence::none().merge(&Fence::none()).unwrap().is_none());
+    }
+
+    #[test]
+    fn merge_with_nothing_dups() {
+        for &(a_is_none, b_is_none) in &[(true, false), (false, true)] {
+            let (fence, write_end) = pipe_fence();
+            let a = if a_is_none { -1 } else { fence };
+            let b = if b_is_none { -1 } else { fence };
+            let merged = merge_fences(a, b);
+            assert!(merged >= 0 && merged != fence);
+            // The input stays the caller's, and the result is another fd
+            // for the same fence.
+            unsafe {
+                close(fence);
+            }
+            assert!(!is_closed(write_end));
+            unsafe {
+                close(merged);
+            }
+            assert!(is_closed(write_end));
+            unsafe {
+                close(write_end);
+            }
+        }
+    }
+
+    // Run with --ignored on a device, desktop kernels seldom have sw_sync.
+    #[test]
+    #[ignore = "needs a kernel with CONFIG_SW_SYNC"]
+    fn merge_signaled() {
+        let (a, b) = (create_signaled_fence(), create_signaled_fence());
+        let (a, b) = (Fence::from_raw(a), Fence::from_raw(b));
+        assert!(!a.is_none() && !b.is_none(), "No sw_sync timeline");
+        let merged = a.merge(&b).unwrap();
+        assert!(!merged.is_none() && merged.is_signaled());
+    }
+}
@@ -0,0 +1,223 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Drives a render callback from the HWC's vsync events.
+
+use error::GonkGfxError;
+use hwc::{self, HwcDevice, HwcEvent};
+use libc::c_int;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+const LOG_TARGET: &'static str = "gonk-gfx::vsync";
+
+// Vsyncs without a queued frame before vsync is turned off, see
+// VsyncScheduler::set_idle_vsyncs.
+const DEFAULT_IDLE_VSYNCS: u32 = 3;
+
+// The device pointer, for the scheduler thread to turn vsync off. The HWC
+// expects eventControl to be called from another thread than composition.
+struct VsyncControl {
+    hwc: *mut hwc::hwc_composer_device,
+    display: c_int,
+    state: Mutex<VsyncState>,
+}
+
+unsafe impl Send for VsyncControl {}
+unsafe impl Sync for VsyncControl {}
+
+struct VsyncState {
+    // Vsync is off until the next wake().
+    idle: bool,
+    // wake() was called while the callback ran.
+    wake_requested: bool,
+    // Whether a window reported queued frames through a VsyncWaker, which
+    // turns on going idle without them.
+    tracking_frames: bool,
+    vsyncs_since_frame: u32,
+    // 0 to never go idle for lack of frames.
+    idle_vsyncs: u32,
+    // The scheduler and its device are gone, wakers can't do anything.
+    closed: bool,
+}
+
+impl VsyncControl {
+    // Turns vsync back on if it went idle, and restarts the count of
+    // vsyncs without frames.
+    fn wake(&self, frame_queued: bool) -> Result<(), GonkGfxError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Ok(());
+        }
+        state.vsyncs_since_frame = 0;
+        if frame_queued {
+            state.tracking_frames = true;
+        }
+        if !state.idle {
+            state.wake_requested = true;
+            return Ok(());
+        }
+        hwc::set_vsync_enabled(self.hwc, self.display, true)?;
+        state.idle = false;
+        Ok(())
+    }
+}
+
+/// Tells a `VsyncScheduler` about the frames a window queues, see
+/// `GonkNativeWindow::set_vsync_waker`.
+#[derive(Clone)]
+pub struct VsyncWaker {
+    control: Arc<VsyncControl>,
+}
+
+impl VsyncWaker {
+    /// Turns vsync back on if the scheduler went idle, and keeps it on for
+    /// another few vsyncs.
+    pub fn frame_queued(&self) {
+        if let Err(err) = self.control.wake(true) {
+            error!(target: LOG_TARGET, "Failed to turn vsync back on: {}", err);
+        }
+    }
+}
+
+/// Calls a callback on a dedicated thread once per vsync of a display.
+///
+/// The callback gets the vsync timestamp and returns whether it wants more
+/// frames. When it returns false, vsync is turned off until `wake` is
+/// called. If the callback is slower than the display, the vsyncs it missed
+/// are dropped and it only sees the latest one.
+///
+/// Once a window reports its frames through a `waker`, vsync is also turned
+/// off after a few vsyncs without a queued frame, whatever the callback
+/// returned, and back on with the next frame.
+///
+/// The scheduler owns the HWC device and its events. Windows can still draw
+/// to it through `hwc().native()`.
+pub struct VsyncScheduler {
+    hwc: Option<HwcDevice>,
+    control: Arc<VsyncControl>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VsyncScheduler {
+    pub fn new<F>(
+        mut hwc: HwcDevice,
+        display: c_int,
+        mut callback: F,
+    ) -> Result<VsyncScheduler, GonkGfxError>
+    where
+        F: FnMut(i64) -> bool + Send + 'static,
+    {
+        let events = hwc.events();
+        let control = Arc::new(VsyncControl {
+            hwc: hwc.native(),
+            display: display,
+            state: Mutex::new(VsyncState {
+                idle: false,
+                wake_requested: false,
+                tracking_frames: false,
+                vsyncs_since_frame: 0,
+                idle_vsyncs: DEFAULT_IDLE_VSYNCS,
+                closed: false,
+            }),
+        });
+        hwc.set_vsync_enabled(display, true)?;
+
+        let thread_control = control.clone();
+        let thread = thread::spawn(move || {
+            // Ends when the HWC device is closed, dropping the sender.
+            while let Ok(event) = events.recv() {
+                let mut latest = vsync_timestamp(event, display);
+                let mut missed = 0;
+                for event in events.try_iter() {
+                    if let Some(timestamp) = vsync_timestamp(event, display) {
+                        if latest.is_some() {
+                            missed += 1;
+                        }
+                        latest = Some(timestamp);
+                    }
+                }
+                let timestamp = match latest {
+                    Some(timestamp) => timestamp,
+                    None => continue,
+                };
+                if missed > 0 {
+                    debug!(target: LOG_TARGET, "Coalesced {} missed vsyncs", missed);
+                }
+
+                let busy = callback(timestamp);
+                let mut state = thread_control.state.lock().unwrap();
+                state.vsyncs_since_frame = state.vsyncs_since_frame.saturating_add(1);
+                let static_screen = state.tracking_frames
+                    && state.idle_vsyncs > 0
+                    && state.vsyncs_since_frame >= state.idle_vsyncs;
+                if (busy && !static_screen) || state.wake_requested {
+                    state.wake_requested = false;
+                } else if !state.idle {
+                    trace!(target: LOG_TARGET, "Going idle");
+                    let _ = hwc::set_vsync_enabled(thread_control.hwc, display, false);
+                    state.idle = true;
+                }
+            }
+            debug!(target: LOG_TARGET, "Vsync thread done");
+        });
+
+        Ok(VsyncScheduler {
+            hwc: Some(hwc),
+            control: control,
+            thread: Some(thread),
+        })
+    }
+
+    /// Turns vsync back on after the callback went idle.
+    pub fn wake(&self) -> Result<(), GonkGfxError> {
+        self.control.wake(false)
+    }
+
+    /// Returns a waker for a window to report its queued frames with.
+    pub fn waker(&self) -> VsyncWaker {
+        VsyncWaker {
+            control: self.control.clone(),
+        }
+    }
+
+    /// Sets how many vsyncs without a queued frame turn vsync off, 3 by
+    /// default, or 0 to leave that to the callback.
+    pub fn set_idle_vsyncs(&self, vsyncs: u32) {
+        self.control.state.lock().unwrap().idle_vsyncs = vsyncs;
+    }
+
+    pub fn hwc(&self) -> &HwcDevice {
+        self.hwc.as_ref().unwrap()
+    }
+}
+
+fn vsync_timestamp(event: HwcEvent, display: c_int) -> Option<i64> {
+    match event {
+        HwcEvent::Vsync {
+            display: vsync_display,
+            timestamp_ns,
+        } if vsync_display == display => Some(timestamp_ns),
+        _ => None,
+    }
+}
+
+impl Drop for VsyncScheduler {
+    fn drop(&mut self) {
+        {
+            // Marked idle, the thread leaves the device alone from now on.
+            let mut state = self.control.state.lock().unwrap();
+            if !state.idle {
+                let _ = self.hwc().set_vsync_enabled(self.control.display, false);
+                state.idle = true;
+            }
+            state.closed = true;
+        }
+        // Closing the device drops the event sender, which stops the thread.
+        self.hwc.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -8,9 +8,10 @@ use egl::{self, EGLContext, EGLDisplay, EGLSurface};
 use hwc::HwcDevice;
 use gleam::gl::{self, Gl};
 use gonk_gfx::*;
-use std::mem::transmute;
 use std::rc::Rc;
 
+const LOG_TARGET: &'static str = "gonk-gfx::window";
+
 /// The type of a window.
 pub struct Window {
     pub width: i32,
@@ -27,7 +28,7 @@ impl Window {
     /// Creates a new window.
     pub fn new() -> Rc<Window> {
         let hwc = HwcDevice::new();
-        assert!(hwc.is_some(), "Failed to get the HWC device");
+        assert!(hwc.is_ok(), "Failed to get the HWC device");
         let hwc = hwc.unwrap();
 
         let (width, height, _dpi) = hwc.get_dimensions_and_dpi();
@@ -40,7 +41,7 @@ impl Window {
 
         assert!(ret, "Failed to initialize EGL!");
 
-        info!("EGL initialized {}.{}", major, minor);
+        info!(target: LOG_TARGET, "EGL initialized {}.{}", major, minor);
 
         let conf_attr = [
             egl::EGL_SURFACE_TYPE,
@@ -55,13 +56,18 @@ impl Window {
         assert!(config.is_some(), "Failed to choose a config");
         let config = config.unwrap();
 
-        info!("Creating {}x{} native window", width, height);
+        info!(target: LOG_TARGET, "Creating {}x{} native window", width, height);
 
         let usage = GRALLOC_USAGE_HW_FB | GRALLOC_USAGE_HW_RENDER | GRALLOC_USAGE_HW_COMPOSER;
-        let native_window = GonkNativeWindow::new(hwc.native(), width, height, usage);
+        let native_window = GonkNativeWindow::new(hwc.native(), width, height, usage)
+            .expect("Failed to create the native window!");
 
-        let surf =
-            unsafe { egl::create_window_surface(dpy, config, transmute(native_window), &[]) };
+        let surf = egl::create_window_surface(
+            dpy,
+            config,
+            native_window_ptr(native_window) as egl::EGLNativeWindowType,
+            &[],
+        );
 
         assert!(surf.is_some());
         let surf = surf.unwrap();
@@ -76,9 +82,8 @@ impl Window {
         let ret = egl::make_current(dpy, surf, surf, ctx);
         assert!(ret, "Failed to make current!");
 
-        unsafe {
-            (*native_window).alloc_buffers();
-        }
+        let ret = unsafe { (*native_window).alloc_buffers() };
+        assert!(ret.is_ok(), "Failed to allocate buffers!");
         hwc.set_display(true);
 
         let gl = unsafe { gl::GlesFns::load_with(|s| egl::get_proc_address(s) as *const _) };
@@ -109,7 +114,7 @@ impl Window {
 
 impl Drop for Window {
     fn drop(&mut self) {
-        info!("Dropping Window");
+        info!(target: LOG_TARGET, "Dropping Window");
         unsafe {
             ((*self.native_window).window.common.dec_ref)(&mut (*self.native_window).window.common);
         }